@@ -1,25 +1,63 @@
-use crate::models::categories::CategoriesResponse;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time::{self, Instant},
+};
 
+use crate::models::{
+    categories::CategoriesResponse,
+    community::{PostsResponse, TopicResponse, TopicsResponse},
+};
+
+// Concurrency model: every actor runs in its own task and owns its state
+// (category map, topic paths, pending assets) outright, handling one event
+// at a time, so no locks are needed. Nothing is shared between actors; what
+// one actor knows reaches another only as an event on this channel. Rayon
+// work inside a handler only borrows the actor's state immutably, and the
+// fetcher's spawned requests share nothing but the data source and its limit
 #[derive(Debug, Clone)]
 pub enum EventType {
     FetcherRequest(FetcherRequest),
     FetcherResponse(FetcherResponse),
     FileRequest(FileRequest),
+    FileWriterResponse(FileWriterResponse),
     UpdateState(StateUpdate),
+    // A failed request is being tried again
+    FetchRetried,
     Shutdown,
+    // The file writer has written everything queued behind Shutdown
+    FileWriterDrained,
+    // A service lost events it can't do without and has stopped; AppState
+    // aborts the export when it sees this
+    Lagged { service: &'static str, missed: u64 },
 }
 
 #[derive(Debug, Clone)]
 pub enum FileRequest {
     Markdown { path: String, data: String },
     Image { path: String, data: Vec<u8> },
+    // A copy of an API response body; written, but not counted as exported
+    Raw { path: String, data: Vec<u8> },
+}
+
+#[derive(Debug, Clone)]
+pub enum FileWriterResponse {
+    WriteFailed { path: String, error: String },
+    // The file was written but the post-write command failed on it
+    CommandFailed { path: String, error: String },
 }
 
 #[derive(Debug, Clone)]
 pub enum StateUpdate {
     Categories(ActiveCount),
+    Community(ActiveCount),
     Fetcher(ActiveCount),
     FileWriter(ActiveCount),
+    ThemeAssets(ActiveCount),
 }
 
 #[derive(Debug, Clone)]
@@ -28,18 +66,183 @@ pub enum ActiveCount {
     Decrement,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FetcherRequest {
     Categories(RequestUrl),
+    Topics(RequestUrl),
+    Topic(RequestUrl),
+    Posts(RequestUrl),
+    ThemePage(RequestUrl),
+    ThemeAsset(RequestUrl),
 }
 
-#[derive(Debug, Clone)]
+impl FetcherRequest {
+    // Short name of the endpoint, for grouping requests in reports
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FetcherRequest::Categories(_) => "categories",
+            FetcherRequest::Topics(_) => "topics",
+            FetcherRequest::Topic(_) => "topic",
+            FetcherRequest::Posts(_) => "posts",
+            FetcherRequest::ThemePage(_) => "theme_page",
+            FetcherRequest::ThemeAsset(_) => "theme_asset",
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.request_url().id
+    }
+
+    pub fn url(&self) -> &str {
+        &self.request_url().url
+    }
+
+    fn request_url(&self) -> &RequestUrl {
+        match self {
+            FetcherRequest::Categories(request_url)
+            | FetcherRequest::Topics(request_url)
+            | FetcherRequest::Topic(request_url)
+            | FetcherRequest::Posts(request_url)
+            | FetcherRequest::ThemePage(request_url)
+            | FetcherRequest::ThemeAsset(request_url) => request_url,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestUrl {
     pub url: String,
+    // Correlates a request with its response in the logs. Ids only mean
+    // something within one run, so a reloaded request gets a fresh one
+    #[serde(skip, default = "next_request_id")]
+    pub id: u64,
+}
+
+impl RequestUrl {
+    pub fn new(url: String) -> Self {
+        RequestUrl {
+            url,
+            id: next_request_id(),
+        }
+    }
+}
+
+fn next_request_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
 }
 
+// Every response carries the id of the request it answers, so a handler's
+// log lines can be matched to the fetcher's
 #[derive(Debug, Clone)]
 pub enum FetcherResponse {
-    Categories(CategoriesResponse),
-    FetchFailed { error: String },
+    Categories(u64, CategoriesResponse),
+    Topics(u64, TopicsResponse),
+    Topic(u64, TopicResponse),
+    Posts(u64, PostsResponse),
+    ThemePage {
+        id: u64,
+        url: String,
+        html: String,
+    },
+    ThemeAsset {
+        id: u64,
+        url: String,
+        data: Vec<u8>,
+    },
+    FetchFailed {
+        request: FetcherRequest,
+        status: Option<u16>,
+        error: String,
+    },
+    // Deliberately not downloaded, e.g. over the size limit. Not a failure, so
+    // it's neither reported nor retried
+    Skipped {
+        request: FetcherRequest,
+        reason: String,
+    },
+}
+
+// For services whose state is wrong once an event is lost: a lag comes back
+// as the number of events missed instead of being skipped
+pub async fn recv_event_or_lag(
+    receiver: &mut broadcast::Receiver<EventType>,
+) -> Option<Result<EventType, u64>> {
+    match receiver.recv().await {
+        Ok(event) => Some(Ok(event)),
+        Err(RecvError::Lagged(missed)) => Some(Err(missed)),
+        Err(RecvError::Closed) => None,
+    }
+}
+
+// Logs a lag and tells AppState about it, for services that stop on one
+pub fn report_lag(sender: &broadcast::Sender<EventType>, service: &'static str, missed: u64) {
+    eprintln!(
+        "{} fell behind and missed {} events, stopping",
+        service, missed
+    );
+    let _ = sender.send(EventType::Lagged { service, missed });
+}
+
+// Longest a service that reports on writes waits for FileWriterDrained after
+// Shutdown, so a wedged file writer can't hold up the reports forever
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Next event for a service, or None once the channel is closed or the
+// deadline, when one is set, passes. A receiver that falls more than the
+// channel's capacity behind loses the oldest events; that's logged and the
+// service keeps going rather than stopping as if shut down
+pub async fn recv_event_until(
+    receiver: &mut broadcast::Receiver<EventType>,
+    service: &str,
+    deadline: Option<Instant>,
+) -> Option<EventType> {
+    loop {
+        match recv_event_or_lag_until(receiver, service, deadline).await {
+            Some(Ok(event)) => return Some(event),
+            Some(Err(missed)) => {
+                eprintln!("{} fell behind and missed {} events", service, missed);
+            }
+            None => return None,
+        }
+    }
+}
+
+// Like recv_event_or_lag, but returns None once the deadline passes, when one is set
+pub async fn recv_event_or_lag_until(
+    receiver: &mut broadcast::Receiver<EventType>,
+    service: &str,
+    deadline: Option<Instant>,
+) -> Option<Result<EventType, u64>> {
+    let Some(deadline) = deadline else {
+        return recv_event_or_lag(receiver).await;
+    };
+    match time::timeout_at(deadline, recv_event_or_lag(receiver)).await {
+        Ok(event) => event,
+        Err(_) => {
+            eprintln!("{} stopped waiting for the file writer to drain", service);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lagging_receiver_skips_or_reports_missed_events() {
+        let (tx, mut lenient) = broadcast::channel(2);
+        let mut strict = tx.subscribe();
+        for _ in 0..3 {
+            let _ = tx.send(EventType::FetchRetried);
+        }
+        let _ = tx.send(EventType::Shutdown);
+
+        assert!(matches!(
+            recv_event_until(&mut lenient, "test", None).await,
+            Some(EventType::FetchRetried)
+        ));
+        assert!(matches!(recv_event_or_lag(&mut strict).await, Some(Err(2))));
+    }
 }
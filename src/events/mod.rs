@@ -1,5 +1,9 @@
 mod events;
 
 pub use events::{
-    ActiveCount, EventType, FetcherRequest, FetcherResponse, FileRequest, RequestUrl, StateUpdate,
+    recv_event_or_lag, recv_event_or_lag_until, recv_event_until, report_lag, DRAIN_TIMEOUT,
+};
+pub use events::{
+    ActiveCount, EventType, FetcherRequest, FetcherResponse, FileRequest, FileWriterResponse,
+    RequestUrl, StateUpdate,
 };
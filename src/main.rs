@@ -1,5 +1,9 @@
-use std::{env, error::Error};
-use tokio::sync::broadcast;
+#![allow(clippy::module_inception)]
+
+use reqwest::Client;
+use std::{env, error::Error, path::Path, thread, time::Duration};
+use tokio::{sync::broadcast, time};
+use url::Url;
 
 mod events;
 mod models;
@@ -7,52 +11,621 @@ mod utils;
 
 use events::EventType;
 use models::{
-    app_state::AppState,
+    app_state::{AppState, AppStateConfig},
     categories::Categories,
-    fetcher::{Fetcher, FetcherConfig},
-    file_writer::FileWriter,
+    community::Community,
+    failure_report::{Failure, FailureReport},
+    fetcher::{Fetcher, FetcherConfig, HttpSource},
+    file_writer::{FileWriter, FileWriterConfig},
+    summary::Summary,
+    theme_assets::ThemeAssets,
 };
 
+use utils::{FrontMatterConfig, Utils};
+
+#[cfg(feature = "metrics")]
+use models::metrics::Metrics;
+
+// Nested output names each locale's directory after its Zendesk code, unless
+// ZENDESK_LOCALE_DIRS maps it to another name, e.g. "en-us=en,pt-br=pt"
+fn locale_dir(locale: &str, mapping: Option<&str>) -> Result<String, String> {
+    let Some(mapping) = mapping else {
+        return Ok(locale.to_string());
+    };
+
+    for entry in mapping.split(',').filter(|entry| !entry.trim().is_empty()) {
+        let Some((from, to)) = entry.split_once('=') else {
+            return Err(format!("Invalid locale mapping: {}", entry));
+        };
+        let to = to.trim();
+        if to.is_empty() || to.contains(['/', '\\']) || to == ".." {
+            return Err(format!("Invalid locale directory: {}", entry));
+        }
+        if from.trim().eq_ignore_ascii_case(locale) {
+            return Ok(to.to_string());
+        }
+    }
+    Ok(locale.to_string())
+}
+
+// Keeps only the scheme and host, e.g. "https://hooks.example.com/<redacted>"
+fn redact_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(url) => format!(
+            "{}://{}/<redacted>",
+            url.scheme(),
+            url.host_str().unwrap_or("")
+        ),
+        Err(_) => "<redacted>".to_string(),
+    }
+}
+
+// How long services get to stop after Shutdown before the process exits anyway
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // Setup channel communications
-    let (tx, _) = broadcast::channel::<EventType>(100);
+    // Sized so a burst of file requests from one page can't push slower receivers
+    // past the point where they start losing events
+    let (tx, _) = broadcast::channel::<EventType>(1024);
     //
     // Configuration from environment variables
     let config = FetcherConfig {
         email: env::var("ZENDESK_EMAIL")?,
         password: env::var("ZENDESK_PASSWORD")?,
-        base_url: "https://nttsh.zendesk.com".to_string(),
+        base_url: FetcherConfig::normalize_base_url(
+            &env::var("ZENDESK_BASE_URL")
+                .unwrap_or_else(|_| "https://nttsh.zendesk.com".to_string()),
+        ),
+        allow_http: env::var("ZENDESK_ALLOW_HTTP").is_ok_and(|value| value == "true"),
         language: "en-001".to_string(),
+        max_retries: match env::var("ZENDESK_MAX_RETRIES") {
+            Ok(retries) => retries.parse()?,
+            Err(_) => 3,
+        },
+        // Comma separated status codes, e.g. "429,503"; empty disables status retries
+        retry_on: env::var("ZENDESK_RETRY_ON")
+            .unwrap_or_else(|_| "429,500,502,503,504".to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|status| !status.is_empty())
+            .map(|status| {
+                status
+                    .parse()
+                    .map_err(|_| format!("Invalid retry status: {}", status))
+            })
+            .collect::<Result<_, _>>()?,
+        max_retry_after: match env::var("ZENDESK_MAX_RETRY_AFTER") {
+            Ok(secs) => Some(Duration::from_secs(secs.parse()?)),
+            Err(_) => None,
+        },
+        retry_budget: match env::var("ZENDESK_RETRY_BUDGET") {
+            Ok(retries) => Some(retries.parse()?),
+            Err(_) => None,
+        },
+        concurrency: match env::var("ZENDESK_CONCURRENCY") {
+            Ok(concurrency) => concurrency.parse()?,
+            Err(_) => 4,
+        },
+        tcp_keepalive: match env::var("ZENDESK_TCP_KEEPALIVE") {
+            Ok(secs) => Duration::from_secs(secs.parse()?),
+            Err(_) => Duration::from_secs(60),
+        },
+        connect_timeout: match env::var("ZENDESK_CONNECT_TIMEOUT") {
+            Ok(secs) => Duration::from_secs(secs.parse()?),
+            Err(_) => Duration::from_secs(30),
+        },
+        max_file_size: match env::var("ZENDESK_MAX_FILE_SIZE") {
+            Ok(bytes) => Some(bytes.parse()?),
+            Err(_) => None,
+        },
+        // Comma separated hosts, e.g. "support.example.com"; only these and the
+        // base url's host get credentials on attachment urls
+        attachment_hosts: env::var("ZENDESK_ATTACHMENT_HOSTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|host| host.trim().to_ascii_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect(),
     };
+    config.validate()?;
 
-    let mut app_state = AppState::new(tx.clone(), tx.subscribe());
-    let mut fetcher = Fetcher::new(config, tx.clone(), tx.subscribe());
-    let mut file_writer = FileWriter::new(tx.clone(), tx.subscribe());
-    let mut categories = Categories::new(tx.clone(), tx.subscribe());
+    // When set, files are written here and only moved into the output directory
+    // once the export has finished, so a failed run can't leave a half-updated
+    // export behind. An unfinished run leaves its files here
+    let output_dir = "data".to_string();
+    let staging_dir = env::var("ZENDESK_STAGING_DIR").ok();
+    if let Some(staging_dir) = &staging_dir {
+        FileWriter::check_staging_dir(staging_dir, &output_dir)?;
+    }
+    // The failures report and metrics go with the files, so a failed staged run
+    // can't replace the last good run's copies either
+    let write_dir = staging_dir.clone().unwrap_or_else(|| output_dir.clone());
 
-    let state_handle = tokio::spawn(async move {
-        app_state.monitor_state().await;
-    });
+    // Output is flat by default; "nested" puts each locale in its own directory
+    let file_writer_config = FileWriterConfig {
+        base_path: write_dir.clone(),
+        locale_dir: match env::var("ZENDESK_OUTPUT_LAYOUT").as_deref() {
+            Ok("nested") => Some(locale_dir(
+                &config.language,
+                env::var("ZENDESK_LOCALE_DIRS").ok().as_deref(),
+            )?),
+            Ok("flat") | Err(_) => None,
+            Ok(layout) => return Err(format!("Unknown output layout: {}", layout).into()),
+        },
+        post_write_command: env::var("ZENDESK_POST_WRITE_COMMAND").ok(),
+    };
+
+    let theme_page_url = format!("{}/hc/{}", config.base_url, config.language);
+
+    // When set, the export summary is posted here as JSON once the export is done.
+    // A failed notification only fails the run if ZENDESK_NOTIFY_REQUIRED=true
+    let notify_url = env::var("ZENDESK_NOTIFY_URL").ok();
+    let notify_required = env::var("ZENDESK_NOTIFY_REQUIRED").is_ok_and(|value| value == "true");
+
+    let front_matter = FrontMatterConfig {
+        // ZENDESK_NO_FRONT_MATTER=true writes bare bodies with no YAML header
+        enabled: !env::var("ZENDESK_NO_FRONT_MATTER").is_ok_and(|value| value == "true"),
+        // Point each exported page back at its Zendesk url
+        source_url: env::var("ZENDESK_SOURCE_URL").is_ok_and(|value| value == "true"),
+        // Front matter dates, e.g. "%Y-%m-%d"; unset keeps Zendesk's timestamps
+        date_format: env::var("ZENDESK_DATE_FORMAT").ok(),
+    };
+    if let Some(format) = &front_matter.date_format {
+        Utils::validate_date_format(format)?;
+    }
+
+    // ZENDESK_COMMUNITY=false leaves out community topics and posts
+    let community_enabled = !env::var("ZENDESK_COMMUNITY").is_ok_and(|value| value == "false");
+
+    // Extra console output, such as the full category list
+    let verbose = env::var("ZENDESK_VERBOSE").is_ok_and(|value| value == "true");
+
+    let app_state_config = AppStateConfig {
+        // Shut down if no service reports progress for this long (default 5 minutes)
+        stall_timeout: match env::var("ZENDESK_STALL_TIMEOUT") {
+            Ok(secs) => Duration::from_secs(secs.parse()?),
+            Err(_) => Duration::from_secs(300),
+        },
+        progress: env::var("ZENDESK_PROGRESS").is_ok_and(|value| value == "true"),
+        strict: env::var("ZENDESK_STRICT").is_ok_and(|value| value == "true"),
+    };
+
+    // Rayon work shares the machine with tokio's workers, so by default it
+    // only gets half the cores
+    let threads = match env::var("ZENDESK_THREADS") {
+        Ok(threads) => threads.parse()?,
+        Err(_) => thread::available_parallelism().map_or(1, |cores| (cores.get() / 2).max(1)),
+    };
+    if threads == 0 {
+        return Err("ZENDESK_THREADS must be at least 1".into());
+    }
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()?;
+
+    // Keep a copy of every API response under raw/ for reprocessing later
+    let dump_raw = env::var("ZENDESK_DUMP_RAW").is_ok_and(|value| value == "true");
+
+    // Retry mode re-issues only the failed fetches listed in a previous report
+    let retry_path = env::var("ZENDESK_RETRY_FAILURES").ok();
+
+    #[cfg(feature = "metrics")]
+    let metrics_path =
+        env::var("ZENDESK_METRICS_FILE").unwrap_or_else(|_| format!("{}/metrics.prom", write_dir));
+
+    // Show what the export actually runs with; the password is redacted, and so
+    // is the notify url's path, which often holds a webhook token
+    println!("{:?}", config);
+    println!("{:?}", file_writer_config);
+    println!("{:?}", app_state_config);
+    println!("{:?}", front_matter);
+    println!(
+        "Options: community={}, verbose={}, dump_raw={}, threads={}",
+        community_enabled, verbose, dump_raw, threads
+    );
+    println!(
+        "Retry failures from: {}",
+        retry_path.as_deref().unwrap_or("<none>")
+    );
+    #[cfg(feature = "metrics")]
+    println!("Metrics file: {}", metrics_path);
+    println!(
+        "Notify url: {}",
+        notify_url
+            .as_deref()
+            .map_or("<none>".to_string(), redact_url)
+    );
+
+    // Keep one idle connection per concurrent request so they get reused
+    // instead of reopened; HTTP/2 is negotiated where the server offers it
+    let client = Client::builder()
+        .pool_max_idle_per_host(config.concurrency)
+        .tcp_keepalive(config.tcp_keepalive)
+        .connect_timeout(config.connect_timeout)
+        .redirect(HttpSource::redirect_policy())
+        .build()?;
+    let concurrency = config.concurrency;
+
+    let source = HttpSource::new(client.clone(), config, tx.clone());
+    source.preflight().await?;
+
+    let mut app_state = AppState::new(app_state_config, tx.clone(), tx.subscribe());
+    let mut fetcher = Fetcher::new(source, concurrency, dump_raw, tx.clone(), tx.subscribe());
+    let mut file_writer = FileWriter::new(file_writer_config, tx.clone(), tx.subscribe());
+    let mut categories = Categories::new(verbose, front_matter.clone(), tx.clone(), tx.subscribe());
+    let mut community = Community::new(community_enabled, front_matter, tx.clone(), tx.subscribe());
+    let mut theme_assets = ThemeAssets::new(theme_page_url, tx.clone(), tx.subscribe());
+    let mut summary = Summary::new(notify_url, client, tx.subscribe());
+
+    // Retry mode only reads the old report; the new one is written with the
+    // files like any other run's, so staging still protects the last good one
+    let report_path = format!("{}/failures.json", write_dir);
+    let mut failure_report = FailureReport::new(report_path, tx.clone(), tx.subscribe());
+
+    if let Some(path) = retry_path {
+        let mut requests = Vec::new();
+        let mut writes = Vec::new();
+        for failure in FailureReport::load(&path)? {
+            match failure {
+                Failure::Fetch { request, .. } => requests.push(request),
+                write => writes.push(write),
+            }
+        }
+
+        println!("Retrying {} failed requests from {}", requests.len(), path);
+        if !writes.is_empty() {
+            println!(
+                "{} failed writes can't be retried on their own; run a full export to recover them",
+                writes.len()
+            );
+        }
+
+        categories.seed(&requests);
+        if community_enabled {
+            community.seed(&requests);
+        }
+        theme_assets.seed(&requests);
+        failure_report.carry_over(writes);
+    }
+
+    #[cfg(feature = "metrics")]
+    let metrics_handle = {
+        let mut metrics = Metrics::new(metrics_path, tx.subscribe());
+        tokio::spawn(async move {
+            metrics.run().await;
+        })
+    };
+
+    let state_handle = tokio::spawn(async move { app_state.monitor_state().await });
 
     let fetcher_handle = tokio::spawn(async move {
         fetcher.run().await;
     });
 
-    let file_writer_handle = tokio::spawn(async move {
-        file_writer.run().await;
-    });
+    let file_writer_handle = tokio::spawn(async move { file_writer.run().await });
 
     let categories_handle = tokio::spawn(async move {
         categories.run().await;
     });
 
-    let _ = tokio::try_join!(
-        state_handle,
-        fetcher_handle,
-        categories_handle,
-        file_writer_handle
-    )?;
+    let community_handle = tokio::spawn(async move {
+        community.run().await;
+    });
+
+    let theme_assets_handle = tokio::spawn(async move {
+        theme_assets.run().await;
+    });
+
+    let failure_report_handle = tokio::spawn(async move { failure_report.run().await });
+
+    let summary_handle = tokio::spawn(async move { summary.run().await });
+
+    let mut export_result = state_handle.await?;
+
+    // A service wedged mid-request won't see Shutdown, so don't wait on it forever
+    let services = async {
+        tokio::try_join!(
+            fetcher_handle,
+            categories_handle,
+            community_handle,
+            file_writer_handle,
+            theme_assets_handle,
+            failure_report_handle
+        )
+    };
+    let finished = match time::timeout(SHUTDOWN_GRACE, services).await {
+        Ok(result) => {
+            let (_, _, _, file_writer_result, _, failure_report_result) = result?;
+            export_result = export_result
+                .and(file_writer_result)
+                .and(failure_report_result);
+            true
+        }
+        Err(_) => {
+            eprintln!(
+                "Services still running {}s after shutdown, exiting anyway.",
+                SHUTDOWN_GRACE.as_secs()
+            );
+            false
+        }
+    };
+
+    // The metrics file is written into the staging dir too, so it has to be
+    // there before the dir is promoted and removed
+    #[cfg(feature = "metrics")]
+    metrics_handle.await?;
+
+    if let Some(staging_dir) = &staging_dir {
+        if finished && export_result.is_ok() {
+            // A clean run writes no report, so the last run's report must not survive
+            if !Path::new(staging_dir).join("failures.json").exists() {
+                let _ = std::fs::remove_file(Path::new(&output_dir).join("failures.json"));
+            }
+            FileWriter::promote(staging_dir, &output_dir)?;
+            println!("Moved staged export from {} to {}", staging_dir, output_dir);
+        } else {
+            eprintln!("Export didn't finish, staged files left in {}", staging_dir);
+        }
+    }
+
+    if let Err(e) = summary_handle.await? {
+        if notify_required {
+            return Err(e.into());
+        }
+    }
+
+    // Whatever was written before the abort is left in place but is incomplete
+    if let Err(reason) = export_result {
+        return Err(format!("Export aborted, output is incomplete: {}", reason).into());
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use events::FetcherResponse;
+    use models::fetcher::ScriptedSource;
+    use std::path::PathBuf;
+
+    #[test]
+    fn locale_dir_uses_the_mapping_when_one_matches() {
+        let mapping = Some("en-us=en, pt-BR = pt");
+        assert_eq!(locale_dir("en-us", mapping).unwrap(), "en");
+        assert_eq!(locale_dir("pt-br", mapping).unwrap(), "pt");
+        assert_eq!(locale_dir("de", mapping).unwrap(), "de");
+        assert_eq!(locale_dir("de", None).unwrap(), "de");
+    }
+
+    #[test]
+    fn locale_dir_rejects_unsafe_targets() {
+        for mapping in ["en-us=", "en-us=../en", "en-us=..", "en-us"] {
+            assert!(locale_dir("en-us", Some(mapping)).is_err(), "{}", mapping);
+        }
+    }
+
+    // Runs the export's services on one task against a scripted source, wired
+    // the way main wires them over `tx`, and returns what AppState and
+    // FileWriter ended with. With `categories_for` set, Categories is dropped
+    // after that long, as if it had died mid-run
+    async fn run_export(
+        source: ScriptedSource,
+        dir: &Path,
+        tx: broadcast::Sender<EventType>,
+        stall_timeout: Duration,
+        categories_for: Option<Duration>,
+    ) -> (Result<(), String>, Result<(), String>) {
+        let page_url = "https://acme.zendesk.com/hc/en-001";
+        let front_matter = FrontMatterConfig {
+            enabled: true,
+            source_url: false,
+            date_format: None,
+        };
+        let app_state_config = AppStateConfig {
+            stall_timeout,
+            progress: false,
+            strict: false,
+        };
+        let file_writer_config = FileWriterConfig {
+            base_path: dir.to_string_lossy().into_owned(),
+            locale_dir: None,
+            post_write_command: None,
+        };
+
+        let mut app_state = AppState::new(app_state_config, tx.clone(), tx.subscribe());
+        let mut fetcher = Fetcher::new(source, 2, false, tx.clone(), tx.subscribe());
+        let mut file_writer = FileWriter::new(file_writer_config, tx.clone(), tx.subscribe());
+        let mut categories =
+            Categories::new(false, front_matter.clone(), tx.clone(), tx.subscribe());
+        let mut community = Community::new(true, front_matter, tx.clone(), tx.subscribe());
+        let mut theme_assets = ThemeAssets::new(page_url.to_string(), tx.clone(), tx.subscribe());
+
+        let categories = async {
+            match categories_for {
+                Some(limit) => {
+                    let _ = time::timeout(limit, categories.run()).await;
+                }
+                None => categories.run().await,
+            }
+        };
+        let services = async {
+            tokio::join!(
+                app_state.monitor_state(),
+                fetcher.run(),
+                file_writer.run(),
+                categories,
+                community.run(),
+                theme_assets.run()
+            )
+        };
+        let (state, _, written, _, _, _) = time::timeout(Duration::from_secs(10), services)
+            .await
+            .expect("export did not shut down");
+        (state, written)
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("zendesk_export_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn exports_categories_and_community_end_to_end() {
+        let dir = test_dir("e2e");
+        let source = ScriptedSource::default()
+            .respond(
+                "categories.json",
+                r#"{"categories": [{"id": 1, "name": "General", "url": "x"}], "next_page": null}"#,
+            )
+            .respond(
+                "topics.json",
+                r#"{"topics": [{"id": 7, "name": "Ideas"}], "next_page": null}"#,
+            )
+            .respond(
+                "topics/7/posts.json",
+                r#"{"posts": [
+                    {"id": 11, "title": "First post", "details": "<p>Hello</p>",
+                     "author_id": 3, "created_at": "2024-05-01T09:30:00Z", "topic_id": 7},
+                    {"id": 12, "title": "First post", "details": "<p>Again</p>",
+                     "author_id": 4, "created_at": "2024-05-02T09:30:00Z", "topic_id": 7},
+                    {"id": 13, "title": "???", "details": "<p>Untitled</p>",
+                     "author_id": 5, "created_at": "2024-05-03T09:30:00Z", "topic_id": 7}],
+                    "next_page": null}"#,
+            )
+            .respond("https://acme.zendesk.com/hc/en-001", "<html></html>");
+
+        let (state, written) = run_export(
+            source,
+            &dir,
+            broadcast::channel(1024).0,
+            Duration::from_secs(10),
+            None,
+        )
+        .await;
+        assert!(state.is_ok());
+        assert!(written.is_ok());
+
+        let read = |path: &str| std::fs::read_to_string(dir.join(path)).unwrap();
+        assert_eq!(
+            read("General/_index.md"),
+            "---\ntitle: \"General\"\n---\n\n"
+        );
+        assert!(read("community/Ideas-7/_index.md").contains("title: \"Ideas\""));
+        let post = read("community/Ideas-7/First_post-11.md");
+        assert!(post.contains("author_id: \"3\""));
+        assert!(post.ends_with("<p>Hello</p>\n"));
+        // Same-titled and untitled posts each keep their own file
+        assert!(read("community/Ideas-7/First_post-12.md").ends_with("<p>Again</p>\n"));
+        assert!(read("community/Ideas-7/13.md").ends_with("<p>Untitled</p>\n"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_service_dying_mid_run_ends_the_export() {
+        let dir = test_dir("dropped");
+        let source = ScriptedSource::default()
+            .respond(
+                "categories.json",
+                r#"{"categories": [], "next_page": null}"#,
+            )
+            .respond("topics.json", r#"{"topics": [], "next_page": null}"#)
+            .delay(Duration::from_millis(300));
+
+        // Categories goes away with its first page still in flight, so that
+        // page is never handled and the stall watchdog has to end the export
+        let (state, _) = run_export(
+            source,
+            &dir,
+            broadcast::channel(1024).0,
+            Duration::from_secs(1),
+            Some(Duration::from_millis(50)),
+        )
+        .await;
+        assert!(state.unwrap_err().starts_with("stalled"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_lagging_service_fails_the_export() {
+        let dir = test_dir("lagged");
+        let posts: Vec<_> = (0..200)
+            .map(|n| {
+                format!(
+                    r#"{{"id": {}, "title": "Post", "details": "", "author_id": 3,
+                        "created_at": "2024-05-01T09:30:00Z", "topic_id": 7}}"#,
+                    n
+                )
+            })
+            .collect();
+        let source = ScriptedSource::default()
+            .respond(
+                "categories.json",
+                r#"{"categories": [], "next_page": null}"#,
+            )
+            .respond(
+                "topics.json",
+                r#"{"topics": [{"id": 7, "name": "Ideas"}], "next_page": null}"#,
+            )
+            .respond(
+                "topics/7/posts.json",
+                &format!(r#"{{"posts": [{}], "next_page": null}}"#, posts.join(",")),
+            );
+
+        // The posts page queues far more file requests than the channel holds
+        // before anything else gets to run, so every other receiver falls behind
+        let (state, written) = run_export(
+            source,
+            &dir,
+            broadcast::channel(32).0,
+            Duration::from_secs(10),
+            None,
+        )
+        .await;
+        assert!(state.unwrap_err().contains("fell behind"));
+        assert!(written.is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_failed_page_keeps_the_pages_before_it() {
+        let dir = test_dir("paged");
+        let page_2 = "categories.json?page=2";
+        let source = ScriptedSource::default()
+            .respond(
+                "categories.json",
+                r#"{"categories": [{"id": 1, "name": "General", "url": "x"}],
+                    "next_page": "https://acme.zendesk.com/api/v2/help_center/categories.json?page=2"}"#,
+            )
+            .fail(page_2, 500)
+            .respond("topics.json", r#"{"topics": [], "next_page": null}"#)
+            .respond("https://acme.zendesk.com/hc/en-001", "<html></html>");
+
+        let (tx, mut events) = broadcast::channel(1024);
+        let (state, written) = run_export(source, &dir, tx, Duration::from_secs(10), None).await;
+        assert!(state.is_ok());
+        assert!(written.is_ok());
+        assert!(dir.join("General/_index.md").exists());
+
+        let mut failed = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let EventType::FetcherResponse(FetcherResponse::FetchFailed {
+                request,
+                status,
+                ..
+            }) = event
+            {
+                failed.push((request.url().to_string(), status));
+            }
+        }
+        assert_eq!(failed, [(page_2.to_string(), Some(500))]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,7 +1,15 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
-use tokio::sync::{broadcast, Mutex};
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::{
+    sync::broadcast,
+    time::{self, Instant},
+};
 
-use crate::events::{ActiveCount, EventType, StateUpdate};
+use crate::events::{
+    recv_event_or_lag, ActiveCount, EventType, FetcherResponse, FileWriterResponse, StateUpdate,
+};
 
 #[derive(Debug, PartialEq)]
 enum CurrentState {
@@ -10,91 +18,259 @@ enum CurrentState {
     Inactive,
 }
 
+// The state is derived from the count rather than stored next to it, so it
+// can't disagree with the count when updates race, and reading it needs no lock
 struct State {
     active_count: AtomicUsize,
-    current_state: Mutex<CurrentState>,
+    started: AtomicBool,
+}
+
+impl State {
+    fn new() -> Self {
+        State {
+            active_count: AtomicUsize::new(0),
+            started: AtomicBool::new(false),
+        }
+    }
+
+    fn current_state(&self) -> CurrentState {
+        if !self.started.load(Ordering::SeqCst) {
+            CurrentState::Initialized
+        } else if self.active_count.load(Ordering::SeqCst) > 0 {
+            CurrentState::Active
+        } else {
+            CurrentState::Inactive
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AppStateConfig {
+    pub stall_timeout: Duration,
+    // Print fetcher gauges once a second while the export runs
+    pub progress: bool,
+    // Shut down on the first failed fetch or write, and report the run as failed
+    pub strict: bool,
 }
 
 pub struct AppState {
     categories: State,
+    community: State,
     fetcher: State,
     file_writer: State,
+    theme_assets: State,
+    shutdown_sent: bool,
+    fetches_requested: usize,
+    fetches_answered: usize,
+    // Set when strict mode, a lost event, a stall or Ctrl-C stopped the export
+    aborted: Option<String>,
+    config: AppStateConfig,
     tx: broadcast::Sender<EventType>,
     rx: broadcast::Receiver<EventType>,
 }
 
 impl AppState {
-    pub fn new(tx: broadcast::Sender<EventType>, rx: broadcast::Receiver<EventType>) -> Self {
+    pub fn new(
+        config: AppStateConfig,
+        tx: broadcast::Sender<EventType>,
+        rx: broadcast::Receiver<EventType>,
+    ) -> Self {
         AppState {
-            categories: State {
-                active_count: AtomicUsize::new(0),
-                current_state: CurrentState::Initialized.into(),
-            },
-            fetcher: State {
-                active_count: AtomicUsize::new(0),
-                current_state: CurrentState::Initialized.into(),
-            },
-            file_writer: State {
-                active_count: AtomicUsize::new(0),
-                current_state: CurrentState::Initialized.into(),
-            },
+            categories: State::new(),
+            community: State::new(),
+            fetcher: State::new(),
+            file_writer: State::new(),
+            theme_assets: State::new(),
+            shutdown_sent: false,
+            fetches_requested: 0,
+            fetches_answered: 0,
+            aborted: None,
+            config,
             tx,
             rx,
         }
     }
 
-    pub async fn monitor_state(&mut self) {
-        while let Ok(update) = self.rx.recv().await {
-            match update {
-                EventType::UpdateState(state_update) => match state_update {
-                    StateUpdate::Categories(count_action) => {
-                        self.update_service_state(&self.categories, count_action)
-                            .await;
-                    }
-                    StateUpdate::Fetcher(count_action) => {
-                        self.update_service_state(&self.fetcher, count_action).await;
-                    }
-                    StateUpdate::FileWriter(count_action) => {
-                        self.update_service_state(&self.file_writer, count_action)
-                            .await;
+    // Returns an error whenever the export stopped before finishing its work
+    pub async fn monitor_state(&mut self) -> Result<(), String> {
+        let mut last_progress = Instant::now();
+        let mut progress_interval = time::interval(Duration::from_secs(1));
+
+        loop {
+            // Watchdog: if no service reports progress for the whole stall timeout,
+            // something is wedged and the export is shut down
+            let update = tokio::select! {
+                // Counts built from a lossy stream can't be trusted, so a lag ends the
+                // export. It may have swallowed Shutdown too, so don't wait for that
+                update = recv_event_or_lag(&mut self.rx) => match update {
+                    Some(Ok(update)) => update,
+                    Some(Err(missed)) => {
+                        self.abort(format!("AppState fell behind and missed {} events", missed));
+                        break;
                     }
+                    None => break,
                 },
+                _ = time::sleep_until(last_progress + self.config.stall_timeout), if !self.shutdown_sent => {
+                    eprintln!("Still active: {}", self.active_services().join(", "));
+                    self.abort(format!("stalled for {}s", self.config.stall_timeout.as_secs()));
+                    continue;
+                }
+                _ = tokio::signal::ctrl_c(), if !self.shutdown_sent => {
+                    self.abort("interrupted".to_string());
+                    continue;
+                }
+                _ = progress_interval.tick(), if self.config.progress => {
+                    self.print_progress();
+                    continue;
+                }
+            };
+
+            match update {
+                EventType::UpdateState(state_update) => {
+                    last_progress = Instant::now();
+                    self.apply_update(state_update);
+                }
+                EventType::FetcherRequest(_) => self.fetches_requested += 1,
+                EventType::FetcherResponse(response) => {
+                    self.fetches_answered += 1;
+                    if let FetcherResponse::FetchFailed { request, error, .. } = response {
+                        self.abort_if_strict(format!(
+                            "{} request {} failed: {}",
+                            request.kind(),
+                            request.url(),
+                            error
+                        ));
+                    }
+                }
+                EventType::FileWriterResponse(FileWriterResponse::WriteFailed { path, error }) => {
+                    self.abort_if_strict(format!("Writing {} failed: {}", path, error));
+                }
+                EventType::FileWriterResponse(FileWriterResponse::CommandFailed {
+                    path,
+                    error,
+                }) => {
+                    self.abort_if_strict(format!(
+                        "Post-write command on {} failed: {}",
+                        path, error
+                    ));
+                }
+                EventType::Lagged { service, missed } => {
+                    self.abort(format!(
+                        "{} fell behind and missed {} events",
+                        service, missed
+                    ));
+                }
                 EventType::Shutdown => {
                     println!("AppState service is shutting down.");
                     break;
                 }
                 _ => {} // Handle other EventType variants if necessary
             }
-            if self.check_all_services_inactive().await {
+            // Shutdown is only broadcast once, even if more updates arrive before
+            // our own Shutdown event comes back around
+            if !self.shutdown_sent && self.check_all_services_inactive() {
                 println!("All services are now inactive.");
+                self.shutdown_sent = true;
                 let _ = self.tx.send(EventType::Shutdown);
             }
         }
+
+        match self.aborted.take() {
+            Some(reason) => Err(reason),
+            None => Ok(()),
+        }
+    }
+
+    fn abort_if_strict(&mut self, reason: String) {
+        if !self.config.strict || self.shutdown_sent {
+            return;
+        }
+        eprintln!("Strict mode: {}.", reason);
+        self.abort(reason);
+    }
+
+    fn abort(&mut self, reason: String) {
+        eprintln!("Aborting export: {}", reason);
+        self.aborted.get_or_insert(reason);
+        if !self.shutdown_sent {
+            self.shutdown_sent = true;
+            let _ = self.tx.send(EventType::Shutdown);
+        }
+    }
+
+    fn apply_update(&self, state_update: StateUpdate) {
+        match state_update {
+            StateUpdate::Categories(count_action) => {
+                self.update_service_state(&self.categories, count_action);
+            }
+            StateUpdate::Community(count_action) => {
+                self.update_service_state(&self.community, count_action);
+            }
+            StateUpdate::Fetcher(count_action) => {
+                self.update_service_state(&self.fetcher, count_action);
+            }
+            StateUpdate::FileWriter(count_action) => {
+                self.update_service_state(&self.file_writer, count_action);
+            }
+            StateUpdate::ThemeAssets(count_action) => {
+                self.update_service_state(&self.theme_assets, count_action);
+            }
+        }
     }
 
-    async fn update_service_state(&self, service_state: &State, action: ActiveCount) {
+    fn update_service_state(&self, service_state: &State, action: ActiveCount) {
         match action {
             ActiveCount::Increment => {
-                let current_count = service_state.active_count.fetch_add(1, Ordering::SeqCst) + 1;
-                let mut state = service_state.current_state.lock().await;
-                if current_count == 1 {
-                    *state = CurrentState::Active;
-                }
+                // Count first, so a service that has just started can never read
+                // as inactive with a zero count
+                service_state.active_count.fetch_add(1, Ordering::SeqCst);
+                service_state.started.store(true, Ordering::SeqCst);
             }
             ActiveCount::Decrement => {
-                let current_count = service_state.active_count.fetch_sub(1, Ordering::SeqCst) - 1;
-                let mut state = service_state.current_state.lock().await;
-                if current_count == 0 {
-                    *state = CurrentState::Inactive;
-                }
+                // A stray decrement must not wrap the count around
+                let _ = service_state.active_count.fetch_update(
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                    |count| count.checked_sub(1),
+                );
             }
         }
     }
 
-    async fn check_all_services_inactive(&self) -> bool {
-        let categories_state = self.categories.current_state.lock().await;
-        let fetcher_state = self.fetcher.current_state.lock().await;
+    // Requests the fetcher is working on, and requests still waiting for it
+    fn print_progress(&self) {
+        let in_flight = self.fetcher.active_count.load(Ordering::SeqCst);
+        let outstanding = self.fetches_requested.saturating_sub(self.fetches_answered);
+        println!(
+            "Fetcher: {} in flight, {} queued, {} done",
+            in_flight,
+            outstanding.saturating_sub(in_flight),
+            self.fetches_answered
+        );
+    }
+
+    fn active_services(&self) -> Vec<&'static str> {
+        [
+            ("categories", &self.categories),
+            ("community", &self.community),
+            ("fetcher", &self.fetcher),
+            ("file_writer", &self.file_writer),
+            ("theme_assets", &self.theme_assets),
+        ]
+        .into_iter()
+        .filter(|(_, service_state)| service_state.current_state() == CurrentState::Active)
+        .map(|(name, _)| name)
+        .collect()
+    }
 
-        *categories_state == CurrentState::Inactive && *fetcher_state == CurrentState::Inactive
+    fn check_all_services_inactive(&self) -> bool {
+        [
+            &self.categories,
+            &self.community,
+            &self.fetcher,
+            &self.theme_assets,
+        ]
+        .iter()
+        .all(|service_state| service_state.current_state() == CurrentState::Inactive)
     }
 }
@@ -1,17 +1,21 @@
 use rayon::prelude::*;
 use serde::Deserialize;
+use std::{collections::BTreeMap, io::IsTerminal};
 use tokio::sync::broadcast;
 
 use crate::events::{
-    ActiveCount, EventType, FetcherRequest, FetcherResponse, FileRequest, RequestUrl, StateUpdate,
+    recv_event_or_lag, report_lag, ActiveCount, EventType, FetcherRequest, FetcherResponse,
+    FileRequest, RequestUrl, StateUpdate,
 };
-use crate::utils::Utils;
+use crate::utils::{FrontMatterConfig, Utils};
 
 #[derive(Deserialize, Debug, Clone)]
 struct Category {
     id: i64,
     name: String,
     url: String,
+    #[serde(default)]
+    html_url: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -22,24 +26,62 @@ pub struct CategoriesResponse {
 
 #[derive(Debug)]
 pub struct Categories {
+    initial_urls: Vec<String>,
+    categories: BTreeMap<i64, Category>,
+    // Print the collected categories once the last page is in
+    verbose: bool,
+    front_matter: FrontMatterConfig,
     sender: broadcast::Sender<EventType>,
     receiver: broadcast::Receiver<EventType>,
 }
 
 impl Categories {
     pub fn new(
+        verbose: bool,
+        front_matter: FrontMatterConfig,
         sender: broadcast::Sender<EventType>,
         receiver: broadcast::Receiver<EventType>,
     ) -> Self {
-        Categories { sender, receiver }
+        Categories {
+            initial_urls: vec!["categories.json".to_string()],
+            categories: BTreeMap::new(),
+            verbose,
+            front_matter,
+            sender,
+            receiver,
+        }
+    }
+
+    // Start from these requests instead of the first categories page
+    pub fn seed(&mut self, requests: &[FetcherRequest]) {
+        self.initial_urls = requests
+            .iter()
+            .filter_map(|request| match request {
+                FetcherRequest::Categories(request_url) => Some(request_url.url.clone()),
+                _ => None,
+            })
+            .collect();
     }
 
     pub async fn run(&mut self) {
-        let initial_url = "categories.json".to_string();
-        let request = FetcherRequest::Categories(RequestUrl { url: initial_url });
-        let _ = self.sender.send(EventType::FetcherRequest(request));
+        // Hold the service active while seeding, so it still goes inactive
+        // when there is nothing to request
+        self.update_state(ActiveCount::Increment);
+        for url in std::mem::take(&mut self.initial_urls) {
+            self.request_page(url);
+        }
+        self.update_state(ActiveCount::Decrement);
 
-        while let Ok(message) = self.receiver.recv().await {
+        while let Some(message) = recv_event_or_lag(&mut self.receiver).await {
+            // A lost response would never be handled or counted down, so a lag
+            // ends the export instead of leaving it to stall
+            let message = match message {
+                Ok(message) => message,
+                Err(missed) => {
+                    report_lag(&self.sender, "Categories", missed);
+                    break;
+                }
+            };
             match message {
                 EventType::FetcherResponse(response) => {
                     self.process_response(response).await;
@@ -55,23 +97,25 @@ impl Categories {
 
     async fn process_response(&mut self, response: FetcherResponse) {
         match response {
-            FetcherResponse::Categories(res) => {
-                let _ = self
-                    .sender
-                    .send(EventType::UpdateState(StateUpdate::Categories(
-                        ActiveCount::Increment,
-                    )));
+            FetcherResponse::Categories(id, res) => {
+                println!("[{}] {} categories", id, res.categories.len());
+                self.categories
+                    .extend(res.categories.iter().map(|cat| (cat.id, cat.clone())));
 
                 // Handle pagination
-                if let Some(next_page) = res.next_page {
-                    let next_page_url = next_page.split('/').last().unwrap_or("").to_string();
-                    let request = FetcherRequest::Categories(RequestUrl { url: next_page_url });
-                    let _ = self.sender.send(EventType::FetcherRequest(request));
+                match res.next_page {
+                    Some(next_page) => {
+                        let next_page_url =
+                            next_page.split('/').next_back().unwrap_or("").to_string();
+                        self.request_page(next_page_url);
+                    }
+                    None if self.verbose => self.print_categories(),
+                    None => {}
                 }
 
                 res.categories.into_par_iter().for_each(|cat| {
                     let sanitized_name = Utils::sanitize_name(&cat.name);
-                    let front_matter = Utils::create_front_matter(&cat.name);
+                    let front_matter = self.front_matter.render(&cat.name, &cat.html_url, vec![]);
                     let path = format!("{}/_index.md", sanitized_name);
 
                     // Send the file write request
@@ -83,15 +127,66 @@ impl Categories {
                         }));
                 });
 
-                let _ = self
-                    .sender
-                    .send(EventType::UpdateState(StateUpdate::Categories(
-                        ActiveCount::Decrement,
-                    )));
+                self.update_state(ActiveCount::Decrement);
             }
-            FetcherResponse::FetchFailed { error } => {
-                eprintln!("Fetch failed: {}", error);
+            FetcherResponse::FetchFailed {
+                request: FetcherRequest::Categories(request_url),
+                error,
+                ..
+            } => {
+                eprintln!(
+                    "[{}] Fetch failed for {}: {}",
+                    request_url.id, request_url.url, error
+                );
+                self.update_state(ActiveCount::Decrement);
             }
+            _ => {}
         }
     }
+
+    // One line per category sorted by id, with aligned columns. Colors are only
+    // used when stdout is a terminal so piped output stays plain
+    fn print_categories(&self) {
+        let id_width = self
+            .categories
+            .keys()
+            .map(|id| id.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let name_width = self
+            .categories
+            .values()
+            .map(|cat| cat.name.chars().count())
+            .max()
+            .unwrap_or(0);
+        let color = std::io::stdout().is_terminal();
+
+        for cat in self.categories.values() {
+            if color {
+                println!(
+                    "\x1b[36m{:>id_width$}\x1b[0m  \x1b[1m{:<name_width$}\x1b[0m  \x1b[2m{}\x1b[0m",
+                    cat.id, cat.name, cat.url
+                );
+            } else {
+                println!(
+                    "{:>id_width$}  {:<name_width$}  {}",
+                    cat.id, cat.name, cat.url
+                );
+            }
+        }
+    }
+
+    // The page counts as active work from the moment it is requested until its
+    // response has been handled, so AppState can't see an idle gap in between
+    fn request_page(&self, url: String) {
+        self.update_state(ActiveCount::Increment);
+        let request = FetcherRequest::Categories(RequestUrl::new(url));
+        let _ = self.sender.send(EventType::FetcherRequest(request));
+    }
+
+    fn update_state(&self, action: ActiveCount) {
+        let _ = self
+            .sender
+            .send(EventType::UpdateState(StateUpdate::Categories(action)));
+    }
 }
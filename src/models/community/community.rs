@@ -0,0 +1,288 @@
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::sync::broadcast;
+
+use crate::events::{
+    recv_event_or_lag, report_lag, ActiveCount, EventType, FetcherRequest, FetcherResponse,
+    FileRequest, RequestUrl, StateUpdate,
+};
+use crate::utils::{FrontMatterConfig, Utils};
+
+#[derive(Deserialize, Debug, Clone)]
+struct Topic {
+    id: i64,
+    name: String,
+    #[serde(default)]
+    html_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TopicsResponse {
+    topics: Vec<Topic>,
+    next_page: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TopicResponse {
+    topic: Topic,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Post {
+    id: i64,
+    title: String,
+    details: String,
+    author_id: i64,
+    created_at: String,
+    topic_id: i64,
+    #[serde(default)]
+    html_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PostsResponse {
+    posts: Vec<Post>,
+    next_page: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Community {
+    initial_requests: Vec<FetcherRequest>,
+    topic_paths: HashMap<i64, String>,
+    seeded_posts: HashMap<i64, Vec<String>>,
+    front_matter: FrontMatterConfig,
+    sender: broadcast::Sender<EventType>,
+    receiver: broadcast::Receiver<EventType>,
+}
+
+impl Community {
+    // A disabled community export requests nothing and goes inactive right away
+    pub fn new(
+        enabled: bool,
+        front_matter: FrontMatterConfig,
+        sender: broadcast::Sender<EventType>,
+        receiver: broadcast::Receiver<EventType>,
+    ) -> Self {
+        let initial_requests = if enabled {
+            vec![FetcherRequest::Topics(RequestUrl::new(
+                "topics.json".to_string(),
+            ))]
+        } else {
+            Vec::new()
+        };
+        Community {
+            initial_requests,
+            topic_paths: HashMap::new(),
+            seeded_posts: HashMap::new(),
+            front_matter,
+            sender,
+            receiver,
+        }
+    }
+
+    // Start from these requests instead of the first topics page. Posts need
+    // their topic's directory, so each topic is looked up before its posts.
+    // A failed topic lookup means none of its posts were fetched, so that
+    // topic's posts are all requested again from the first page
+    pub fn seed(&mut self, requests: &[FetcherRequest]) {
+        self.initial_requests.clear();
+        for request in requests {
+            let posts_url = match request {
+                FetcherRequest::Topics(_) => {
+                    self.initial_requests.push(request.clone());
+                    continue;
+                }
+                FetcherRequest::Topic(request_url) => topic_id(&request_url.url)
+                    .map(|topic_id| format!("topics/{}/posts.json", topic_id)),
+                FetcherRequest::Posts(request_url) => Some(request_url.url.clone()),
+                _ => None,
+            };
+            let Some((topic_id, posts_url)) =
+                posts_url.and_then(|url| topic_id(&url).map(|topic_id| (topic_id, url)))
+            else {
+                continue;
+            };
+
+            let posts = self.seeded_posts.entry(topic_id).or_default();
+            if posts.is_empty() {
+                self.initial_requests
+                    .push(FetcherRequest::Topic(RequestUrl::new(format!(
+                        "topics/{}.json",
+                        topic_id
+                    ))));
+            }
+            if !posts.contains(&posts_url) {
+                posts.push(posts_url);
+            }
+        }
+    }
+
+    pub async fn run(&mut self) {
+        // Hold the service active while seeding, so it still goes inactive
+        // when there is nothing to request
+        self.update_state(ActiveCount::Increment);
+        for request in std::mem::take(&mut self.initial_requests) {
+            self.update_state(ActiveCount::Increment);
+            let _ = self.sender.send(EventType::FetcherRequest(request));
+        }
+        self.update_state(ActiveCount::Decrement);
+
+        while let Some(message) = recv_event_or_lag(&mut self.receiver).await {
+            // A lost response would never be handled or counted down, so a lag
+            // ends the export instead of leaving it to stall
+            let message = match message {
+                Ok(message) => message,
+                Err(missed) => {
+                    report_lag(&self.sender, "Community", missed);
+                    break;
+                }
+            };
+            match message {
+                EventType::FetcherResponse(response) => {
+                    self.process_response(response).await;
+                }
+                EventType::Shutdown => {
+                    println!("Community service is shutting down.");
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn process_response(&mut self, response: FetcherResponse) {
+        match response {
+            FetcherResponse::Topics(id, res) => {
+                println!("[{}] {} topics", id, res.topics.len());
+                if let Some(next_page) = res.next_page {
+                    self.request(community_path(&next_page), FetcherRequest::Topics);
+                }
+
+                for topic in res.topics {
+                    let topic_id = topic.id;
+                    self.add_topic(topic);
+
+                    let posts_url = format!("topics/{}/posts.json", topic_id);
+                    self.request(posts_url, FetcherRequest::Posts);
+                }
+
+                self.update_state(ActiveCount::Decrement);
+            }
+            FetcherResponse::Topic(id, res) => {
+                println!("[{}] Topic {}", id, res.topic.id);
+                let topic_id = res.topic.id;
+                self.add_topic(res.topic);
+
+                for posts_url in self.seeded_posts.remove(&topic_id).unwrap_or_default() {
+                    self.request(posts_url, FetcherRequest::Posts);
+                }
+
+                self.update_state(ActiveCount::Decrement);
+            }
+            FetcherResponse::Posts(id, res) => {
+                println!("[{}] {} posts", id, res.posts.len());
+                if let Some(next_page) = res.next_page {
+                    self.request(community_path(&next_page), FetcherRequest::Posts);
+                }
+
+                res.posts.into_par_iter().for_each(|post| {
+                    let Some(topic_path) = self.topic_paths.get(&post.topic_id) else {
+                        return;
+                    };
+                    let front_matter = self.front_matter.render(
+                        &post.title,
+                        &post.html_url,
+                        vec![
+                            ("author_id", post.author_id.to_string()),
+                            ("created_at", self.front_matter.date(post.created_at)),
+                        ],
+                    );
+                    let path = format!("{}/{}.md", topic_path, entry_name(&post.title, post.id));
+
+                    // Post bodies are kept as HTML, which Markdown renders as-is
+                    let _ = self
+                        .sender
+                        .send(EventType::FileRequest(FileRequest::Markdown {
+                            path,
+                            data: format!("{}{}\n", front_matter, post.details),
+                        }));
+                });
+
+                self.update_state(ActiveCount::Decrement);
+            }
+            FetcherResponse::Skipped {
+                request: FetcherRequest::Topics(request_url),
+                reason,
+            } => {
+                println!("[{}] Skipping community: {}", request_url.id, reason);
+                self.update_state(ActiveCount::Decrement);
+            }
+            FetcherResponse::FetchFailed {
+                request:
+                    FetcherRequest::Topics(request_url)
+                    | FetcherRequest::Topic(request_url)
+                    | FetcherRequest::Posts(request_url),
+                error,
+                ..
+            } => {
+                eprintln!(
+                    "[{}] Fetch failed for {}: {}",
+                    request_url.id, request_url.url, error
+                );
+                self.update_state(ActiveCount::Decrement);
+            }
+            _ => {}
+        }
+    }
+
+    fn add_topic(&mut self, topic: Topic) {
+        let path = format!("community/{}", entry_name(&topic.name, topic.id));
+        let _ = self
+            .sender
+            .send(EventType::FileRequest(FileRequest::Markdown {
+                path: format!("{}/_index.md", path),
+                data: self
+                    .front_matter
+                    .render(&topic.name, &topic.html_url, vec![]),
+            }));
+        self.topic_paths.insert(topic.id, path);
+    }
+
+    // Each request stays active until its response has been handled
+    fn request(&self, url: String, request: fn(RequestUrl) -> FetcherRequest) {
+        self.update_state(ActiveCount::Increment);
+        let _ = self
+            .sender
+            .send(EventType::FetcherRequest(request(RequestUrl::new(url))));
+    }
+
+    fn update_state(&self, action: ActiveCount) {
+        let _ = self
+            .sender
+            .send(EventType::UpdateState(StateUpdate::Community(action)));
+    }
+}
+
+// Topic names and post titles can repeat, so the id keeps their files apart.
+// A name with nothing left once sanitized is just the id
+fn entry_name(name: &str, id: i64) -> String {
+    match Utils::sanitize_name(name) {
+        sanitized if sanitized.is_empty() => id.to_string(),
+        sanitized => format!("{}-{}", sanitized, id),
+    }
+}
+
+// The topic a `topics/{id}.json` or `topics/{id}/posts.json` path belongs to
+fn topic_id(path: &str) -> Option<i64> {
+    path.strip_prefix("topics/")
+        .and_then(|rest| rest.split(['/', '.']).next())
+        .and_then(|id| id.parse().ok())
+}
+
+// Pagination links are absolute; the fetcher expects the path below `community/`
+fn community_path(url: &str) -> String {
+    url.split_once("/community/")
+        .map(|(_, path)| path.to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,3 @@
+mod community;
+
+pub use community::{Community, PostsResponse, TopicResponse, TopicsResponse};
@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, error::Error, io::ErrorKind, path::Path};
+use tokio::{fs, sync::broadcast, time::Instant};
+
+use crate::events::{
+    recv_event_or_lag_until, report_lag, EventType, FetcherRequest, FetcherResponse,
+    FileWriterResponse, DRAIN_TIMEOUT,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Failure {
+    Fetch {
+        request: FetcherRequest,
+        status: Option<u16>,
+        error: String,
+    },
+    Write {
+        path: String,
+        error: String,
+    },
+    // The file was written but the post-write command failed on it
+    Command {
+        path: String,
+        error: String,
+    },
+}
+
+// Collects every failed fetch and write and lists them in a single report
+// once the export has shut down and the file writer has drained
+pub struct FailureReport {
+    path: String,
+    failures: Vec<Failure>,
+    sender: broadcast::Sender<EventType>,
+    receiver: broadcast::Receiver<EventType>,
+}
+
+impl FailureReport {
+    pub fn new(
+        path: String,
+        sender: broadcast::Sender<EventType>,
+        receiver: broadcast::Receiver<EventType>,
+    ) -> Self {
+        FailureReport {
+            path,
+            failures: Vec::new(),
+            sender,
+            receiver,
+        }
+    }
+
+    // Read the report written by a previous run
+    pub fn load(path: &str) -> Result<Vec<Failure>, Box<dyn Error>> {
+        let data = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    // Failures that can't be retried are carried into the new report so they
+    // aren't forgotten
+    pub fn carry_over(&mut self, failures: Vec<Failure>) {
+        self.failures.extend(failures);
+    }
+
+    // A lost FetchFailed would leave that request out of what retry mode can
+    // re-issue, so a lag fails the export. The partial report is still written
+    pub async fn run(&mut self) -> Result<(), String> {
+        // Set at Shutdown; writes queued behind it can still fail until the
+        // file writer reports it has drained
+        let mut drain_deadline = None;
+        let mut missed = 0;
+        while let Some(event) =
+            recv_event_or_lag_until(&mut self.receiver, "FailureReport", drain_deadline).await
+        {
+            let event = match event {
+                Ok(event) => event,
+                Err(lagged) => {
+                    report_lag(&self.sender, "FailureReport", lagged);
+                    missed = lagged;
+                    break;
+                }
+            };
+            match event {
+                EventType::FetcherResponse(FetcherResponse::FetchFailed {
+                    request,
+                    status,
+                    error,
+                }) => {
+                    self.failures.push(Failure::Fetch {
+                        request,
+                        status,
+                        error,
+                    });
+                }
+                EventType::FileWriterResponse(FileWriterResponse::WriteFailed { path, error }) => {
+                    self.failures.push(Failure::Write { path, error });
+                }
+                EventType::FileWriterResponse(FileWriterResponse::CommandFailed {
+                    path,
+                    error,
+                }) => {
+                    self.failures.push(Failure::Command { path, error });
+                }
+                EventType::Shutdown => drain_deadline = Some(Instant::now() + DRAIN_TIMEOUT),
+                EventType::FileWriterDrained => break,
+                _ => {}
+            }
+        }
+
+        self.print_permission_errors();
+        self.write_report().await;
+        println!("FailureReport service is shutting down.");
+
+        if missed > 0 {
+            return Err(format!(
+                "FailureReport missed {} events, the failures report is incomplete",
+                missed
+            ));
+        }
+        Ok(())
+    }
+
+    // 403s usually mean the account lacks access to a whole endpoint, so they're
+    // summarized per endpoint rather than left scattered through the report
+    fn print_permission_errors(&self) {
+        let mut forbidden: BTreeMap<&str, usize> = BTreeMap::new();
+        for failure in &self.failures {
+            if let Failure::Fetch {
+                request,
+                status: Some(403),
+                ..
+            } = failure
+            {
+                *forbidden.entry(request.kind()).or_insert(0) += 1;
+            }
+        }
+
+        for (kind, count) in forbidden {
+            eprintln!(
+                "{} export incomplete: insufficient permissions (403) on {} requests",
+                kind, count
+            );
+        }
+    }
+
+    async fn write_report(&self) {
+        let path = Path::new(&self.path);
+
+        // A clean run leaves no report behind, including one from a previous run
+        if self.failures.is_empty() {
+            if let Err(e) = fs::remove_file(path).await {
+                if e.kind() != ErrorKind::NotFound {
+                    eprintln!("Failed to remove stale failures report: {}", e);
+                }
+            }
+            return;
+        }
+
+        let data = match serde_json::to_string_pretty(&self.failures) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("Failed to serialize failures report: {}", e);
+                return;
+            }
+        };
+
+        if let Some(dir) = path.parent() {
+            if let Err(e) = fs::create_dir_all(dir).await {
+                eprintln!("Failed to create failures report directory: {}", e);
+                return;
+            }
+        }
+
+        match fs::write(path, data).await {
+            Ok(_) => println!(
+                "{} failures written to: {}",
+                self.failures.len(),
+                path.display()
+            ),
+            Err(e) => eprintln!("Failed to write failures report: {}", e),
+        }
+    }
+}
@@ -0,0 +1,3 @@
+mod failure_report;
+
+pub use failure_report::{Failure, FailureReport};
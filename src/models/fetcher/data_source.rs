@@ -0,0 +1,570 @@
+use reqwest::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    redirect, Client, Error as ReqwestError, StatusCode,
+};
+use std::{
+    fmt,
+    future::Future,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::{net, sync::broadcast, time};
+use url::Url;
+
+use super::FetcherConfig;
+use crate::events::{EventType, FetcherRequest};
+
+// Where the fetcher gets response bodies from. Keeping this behind a trait
+// lets the actors run against something other than the live API
+pub trait DataSource {
+    fn fetch(
+        &self,
+        request: &FetcherRequest,
+    ) -> impl Future<Output = Result<Vec<u8>, FetchError>> + Send;
+}
+
+#[derive(Debug, Clone)]
+pub struct FetchError {
+    pub status: Option<u16>,
+    pub message: String,
+    // Deliberately not fetched, e.g. over the size limit, rather than failed
+    pub skipped: bool,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<ReqwestError> for FetchError {
+    fn from(e: ReqwestError) -> Self {
+        FetchError {
+            status: e.status().map(|status| status.as_u16()),
+            message: format!("Failed to fetch data: {}", e),
+            skipped: false,
+        }
+    }
+}
+
+pub struct HttpSource {
+    client: Client,
+    config: FetcherConfig,
+    // Requests and retries across the whole run, for the retry budget
+    fetches: AtomicU64,
+    retries: AtomicU64,
+    budget_spent: AtomicBool,
+    sender: broadcast::Sender<EventType>,
+}
+
+// The default budget never drops below this, so small exports can still retry
+const MIN_RETRY_BUDGET: u64 = 10;
+
+// All of the preflight gets this long, so a dead host is reported quickly
+const PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// A vanity domain that redirects in a loop gives up quickly instead of after
+// reqwest's default of 10 hops
+const MAX_REDIRECTS: usize = 5;
+
+impl HttpSource {
+    pub fn new(
+        client: Client,
+        config: FetcherConfig,
+        sender: broadcast::Sender<EventType>,
+    ) -> Self {
+        HttpSource {
+            client,
+            config,
+            fetches: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            budget_spent: AtomicBool::new(false),
+            sender,
+        }
+    }
+
+    // Every retry comes out of one budget for the whole run, so a badly degraded
+    // instance fails the remaining requests fast instead of retrying forever.
+    // Without a configured budget, one retry per ten requests is allowed
+    fn take_retry(&self) -> bool {
+        let budget = self
+            .config
+            .retry_budget
+            .unwrap_or_else(|| (self.fetches.load(Ordering::SeqCst) / 10).max(MIN_RETRY_BUDGET));
+        let taken = self
+            .retries
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |retries| {
+                (retries < budget).then_some(retries + 1)
+            })
+            .is_ok();
+
+        if taken {
+            let _ = self.sender.send(EventType::FetchRetried);
+        } else if !self.budget_spent.swap(true, Ordering::SeqCst) {
+            eprintln!(
+                "Retry budget of {} spent, failing further errors without retrying",
+                budget
+            );
+        }
+        taken
+    }
+
+    // The server's Retry-After is honored in full unless a cap is configured
+    fn cap_retry_after(&self, url: &str, retry_after: Duration) -> Duration {
+        match self.config.max_retry_after {
+            Some(cap) if retry_after > cap => {
+                eprintln!(
+                    "Retry-After of {}s for {} capped to {}s",
+                    retry_after.as_secs(),
+                    url,
+                    cap.as_secs()
+                );
+                cap
+            }
+            _ => retry_after,
+        }
+    }
+
+    // Attachments and API urls on the instance's own hosts can need the same basic
+    // auth as the API, but other tenants and public CDN hosts must never see the
+    // credentials. Url parsing has already lowercased the host
+    fn needs_auth(&self, url: &str) -> bool {
+        let (Ok(url), Ok(base)) = (Url::parse(url), Url::parse(&self.config.base_url)) else {
+            return false;
+        };
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+
+        let trusted_host = Some(host) == base.host_str()
+            || self
+                .config
+                .attachment_hosts
+                .iter()
+                .any(|trusted| trusted == host);
+        let protected_path = ["/api/", "/hc/article_attachments/", "/attachments/"]
+            .iter()
+            .any(|prefix| url.path().starts_with(prefix));
+        url.scheme() == base.scheme() && trusted_host && protected_path
+    }
+
+    // Check DNS, the Help Center and the credentials before anything else runs,
+    // so a bad setup fails with one clear message instead of a cascade of errors
+    pub async fn preflight(&self) -> Result<(), String> {
+        time::timeout(PREFLIGHT_TIMEOUT, self.preflight_checks())
+            .await
+            .map_err(|_| {
+                format!(
+                    "Preflight: {} didn't answer within {}s",
+                    self.config.base_url,
+                    PREFLIGHT_TIMEOUT.as_secs()
+                )
+            })?
+    }
+
+    async fn preflight_checks(&self) -> Result<(), String> {
+        let url = Url::parse(&self.config.base_url)
+            .map_err(|e| format!("Invalid base url {}: {}", self.config.base_url, e))?;
+        let host = url.host_str().unwrap_or("").to_string();
+        let port = url.port_or_known_default().unwrap_or(443);
+
+        match net::lookup_host((host.as_str(), port)).await {
+            Ok(_) => println!("Preflight: resolved {}", host),
+            Err(e) => return Err(format!("Preflight: can't resolve {}: {}", host, e)),
+        }
+
+        // Host-mapped, restricted and disabled Help Centers all answer this with
+        // an error while the API still works, so it's only a warning
+        let help_center = format!("{}/hc", self.config.base_url);
+        match self
+            .client
+            .get(&help_center)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+        {
+            Ok(_) => println!("Preflight: reached {}", help_center),
+            Err(e) => eprintln!("Preflight: warning, can't reach {}: {}", help_center, e),
+        }
+
+        // Zendesk answers bad credentials with an anonymous user rather than a 401
+        let me: serde_json::Value = self
+            .client
+            .get(self.api_endpoint("users/me.json"))
+            .basic_auth(&self.config.email, Some(&self.config.password))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("Preflight: auth check failed: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("Preflight: auth check failed: {}", e))?;
+        if me["user"]["id"].is_null() {
+            return Err(format!(
+                "Preflight: {} was not accepted, check ZENDESK_EMAIL and ZENDESK_PASSWORD",
+                self.config.email
+            ));
+        }
+        println!("Preflight: authenticated as {}", self.config.email);
+        Ok(())
+    }
+
+    // Redirects are only followed on the host the request started on, so basic
+    // auth credentials are never sent anywhere else
+    pub fn redirect_policy() -> redirect::Policy {
+        redirect::Policy::custom(|attempt| {
+            let from = attempt.previous()[0].host_str().unwrap_or("").to_string();
+            let to = attempt.url().host_str().unwrap_or("").to_string();
+            if attempt.previous().len() > MAX_REDIRECTS {
+                attempt.error(format!("more than {} redirects", MAX_REDIRECTS))
+            } else if from != to {
+                eprintln!("Refused redirect from {} to {}", from, to);
+                attempt.error(format!("refused redirect from {} to {}", from, to))
+            } else {
+                attempt.follow()
+            }
+        })
+    }
+
+    fn api_endpoint(&self, path: &str) -> String {
+        format!("{}/api/v2/{}", self.config.base_url, path)
+    }
+
+    // Statuses are only retried when configured; connection problems and
+    // timeouts are always worth another try
+    fn should_retry(&self, error: &ReqwestError) -> bool {
+        match error.status() {
+            Some(status) => self.config.retry_on.contains(&status.as_u16()),
+            None => error.is_connect() || error.is_timeout(),
+        }
+    }
+}
+
+impl DataSource for HttpSource {
+    async fn fetch(&self, request: &FetcherRequest) -> Result<Vec<u8>, FetchError> {
+        self.fetches.fetch_add(1, Ordering::SeqCst);
+        let (url, authenticated, max_size) = match request {
+            FetcherRequest::Categories(request_url) => {
+                let path = format!("help_center/{}/{}", self.config.language, request_url.url);
+                (self.api_endpoint(&path), true, None)
+            }
+            FetcherRequest::Topics(request_url)
+            | FetcherRequest::Topic(request_url)
+            | FetcherRequest::Posts(request_url) => {
+                let path = format!("community/{}", request_url.url);
+                (self.api_endpoint(&path), true, None)
+            }
+            // Help Center pages and public assets get no credentials; only downloaded
+            // files are held to the size limit
+            FetcherRequest::ThemePage(request_url) => (request_url.url.clone(), false, None),
+            FetcherRequest::ThemeAsset(request_url) => (
+                request_url.url.clone(),
+                self.needs_auth(&request_url.url),
+                self.config.max_file_size,
+            ),
+        };
+
+        // API responses are always JSON, so an empty body is a glitch, not data
+        let expects_json = !matches!(
+            request,
+            FetcherRequest::ThemePage(_) | FetcherRequest::ThemeAsset(_)
+        );
+
+        // Instances without the community module answer its first page with a
+        // 403 or 404; that's not a failed export
+        let first_topics_page = matches!(
+            request,
+            FetcherRequest::Topics(request_url) if request_url.url == "topics.json"
+        );
+
+        let mut attempt = 0;
+        loop {
+            let mut request = self.client.get(&url);
+            if authenticated {
+                request = request.basic_auth(&self.config.email, Some(&self.config.password));
+            }
+
+            match fetch_bytes(request, max_size).await {
+                Ok(body) if expects_json && body.trim_ascii().is_empty() => {
+                    if attempt >= self.config.max_retries || !self.take_retry() {
+                        return Err(FetchError {
+                            status: None,
+                            message: format!("Empty response from {}", url),
+                            skipped: false,
+                        });
+                    }
+                    attempt += 1;
+                    let delay = retry_delay(attempt);
+                    eprintln!(
+                        "Empty response from {}, retrying in {}ms (attempt {} of {})",
+                        url,
+                        delay.as_millis(),
+                        attempt,
+                        self.config.max_retries
+                    );
+                    time::sleep(delay).await;
+                }
+                Ok(body) => return Ok(body),
+                Err(AttemptError::TooLarge(limit)) => {
+                    eprintln!("Skipped {}: larger than {} bytes", url, limit);
+                    return Err(FetchError {
+                        status: None,
+                        message: format!("Larger than {} bytes", limit),
+                        skipped: true,
+                    });
+                }
+                Err(AttemptError::Maintenance)
+                    if attempt < self.config.max_retries && self.take_retry() =>
+                {
+                    attempt += 1;
+                    eprintln!(
+                        "Zendesk appears to be in maintenance, retrying {} in {}s (attempt {} of {})",
+                        url,
+                        MAINTENANCE_DELAY.as_secs(),
+                        attempt,
+                        self.config.max_retries
+                    );
+                    time::sleep(MAINTENANCE_DELAY).await;
+                }
+                Err(AttemptError::Maintenance) => {
+                    return Err(FetchError {
+                        status: Some(503),
+                        message: "Zendesk appears to be in maintenance".to_string(),
+                        skipped: false,
+                    })
+                }
+                Err(AttemptError::Request(e, retry_after))
+                    if attempt < self.config.max_retries
+                        && self.should_retry(&e)
+                        && self.take_retry() =>
+                {
+                    attempt += 1;
+                    let delay = match retry_after {
+                        Some(after) => self.cap_retry_after(&url, after),
+                        None => retry_delay(attempt),
+                    };
+                    eprintln!(
+                        "Retrying {} in {}ms (attempt {} of {}): {}",
+                        url,
+                        delay.as_millis(),
+                        attempt,
+                        self.config.max_retries,
+                        e
+                    );
+                    time::sleep(delay).await;
+                }
+                Err(AttemptError::Request(e, _))
+                    if first_topics_page
+                        && matches!(e.status().map(|status| status.as_u16()), Some(403 | 404)) =>
+                {
+                    return Err(FetchError {
+                        status: e.status().map(|status| status.as_u16()),
+                        message: "Community is not enabled on this instance".to_string(),
+                        skipped: true,
+                    })
+                }
+                Err(AttemptError::Request(e, _)) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+// Exponential backoff starting at half a second, capped at 32s
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_millis(500 << (attempt - 1).min(6))
+}
+
+// Maintenance windows last minutes, so those retries wait much longer
+const MAINTENANCE_DELAY: Duration = Duration::from_secs(60);
+
+// Why a single attempt failed
+enum AttemptError {
+    // Zendesk serves an HTML 503 page instead of JSON while it's down for maintenance
+    Maintenance,
+    // The body is bigger than the configured limit
+    TooLarge(u64),
+    // Carries the server's Retry-After, when it sent one
+    Request(ReqwestError, Option<Duration>),
+}
+
+impl From<ReqwestError> for AttemptError {
+    fn from(e: ReqwestError) -> Self {
+        AttemptError::Request(e, None)
+    }
+}
+
+async fn fetch_bytes(
+    request: reqwest::RequestBuilder,
+    max_size: Option<u64>,
+) -> Result<Vec<u8>, AttemptError> {
+    let mut response = request.send().await?;
+    let is_html = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"));
+    if response.status() == StatusCode::SERVICE_UNAVAILABLE && is_html {
+        return Err(AttemptError::Maintenance);
+    }
+
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs);
+    if let Err(e) = response.error_for_status_ref() {
+        return Err(AttemptError::Request(e, retry_after));
+    }
+
+    let Some(limit) = max_size else {
+        return Ok(response.bytes().await?.to_vec());
+    };
+
+    // Content-Length can be missing or wrong, so the body is also checked as it streams in
+    if response
+        .content_length()
+        .is_some_and(|length| length > limit)
+    {
+        return Err(AttemptError::TooLarge(limit));
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() as u64 > limit {
+            return Err(AttemptError::TooLarge(limit));
+        }
+    }
+    Ok(body)
+}
+
+// Canned responses keyed by request url, so the actors can be run in tests
+// without a live instance. Urls without a response fail with a 404
+#[cfg(test)]
+#[derive(Default)]
+pub struct ScriptedSource {
+    responses: std::collections::HashMap<String, Result<Vec<u8>, FetchError>>,
+    delay: Duration,
+}
+
+#[cfg(test)]
+impl ScriptedSource {
+    pub fn respond(mut self, url: &str, body: &str) -> Self {
+        self.responses
+            .insert(url.to_string(), Ok(body.as_bytes().to_vec()));
+        self
+    }
+
+    pub fn fail(mut self, url: &str, status: u16) -> Self {
+        let error = FetchError {
+            status: Some(status),
+            message: format!("Scripted {} for {}", status, url),
+            skipped: false,
+        };
+        self.responses.insert(url.to_string(), Err(error));
+        self
+    }
+
+    // Every response waits this long, like a slow server
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+#[cfg(test)]
+impl DataSource for ScriptedSource {
+    async fn fetch(&self, request: &FetcherRequest) -> Result<Vec<u8>, FetchError> {
+        time::sleep(self.delay).await;
+        self.responses
+            .get(request.url())
+            .cloned()
+            .unwrap_or_else(|| {
+                Err(FetchError {
+                    status: Some(404),
+                    message: format!("No scripted response for {}", request.url()),
+                    skipped: false,
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::RequestUrl;
+
+    fn source(attachment_hosts: &[&str]) -> HttpSource {
+        let mut config = FetcherConfig::for_tests("https://acme.zendesk.com");
+        config.attachment_hosts = attachment_hosts.iter().map(|h| h.to_string()).collect();
+        let (tx, _) = broadcast::channel(16);
+        HttpSource::new(Client::new(), config, tx)
+    }
+
+    #[test]
+    fn auth_is_sent_to_the_base_host() {
+        let source = source(&[]);
+        assert!(source.needs_auth("https://acme.zendesk.com/hc/article_attachments/1/a.png"));
+        assert!(source.needs_auth("https://ACME.zendesk.com/attachments/token/x/a.png"));
+        assert!(!source.needs_auth("https://acme.zendesk.com/hc/theming_assets/a.css"));
+        assert!(!source.needs_auth("http://acme.zendesk.com/attachments/token/x/a.png"));
+    }
+
+    #[test]
+    fn auth_is_sent_only_to_listed_attachment_hosts() {
+        let source = source(&["support.acme.com"]);
+        assert!(source.needs_auth("https://support.acme.com/hc/article_attachments/1/a.png"));
+        assert!(!source.needs_auth("https://other.zendesk.com/hc/article_attachments/1/a.png"));
+        assert!(!source.needs_auth("https://cdn.acme.com/hc/article_attachments/1/a.png"));
+    }
+
+    // Answers every request on a local port with a 200 and an empty body
+    async fn empty_body_server() -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await;
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn empty_body_fails_api_requests_but_not_assets() {
+        let base_url = empty_body_server().await;
+        let (tx, _) = broadcast::channel(16);
+        let source = HttpSource::new(Client::new(), FetcherConfig::for_tests(&base_url), tx);
+
+        let api = FetcherRequest::Categories(RequestUrl::new("categories.json".to_string()));
+        let error = source.fetch(&api).await.unwrap_err();
+        assert!(error.message.starts_with("Empty response"));
+
+        let asset = FetcherRequest::ThemeAsset(RequestUrl::new(format!("{}/a.css", base_url)));
+        assert!(source.fetch(&asset).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn preflight_gives_up_on_a_host_that_never_answers() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut open = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                open.push(stream);
+            }
+        });
+        let (tx, _) = broadcast::channel(16);
+        let source = HttpSource::new(Client::new(), FetcherConfig::for_tests(&base_url), tx);
+
+        let started = std::time::Instant::now();
+        assert!(source.preflight().await.is_err());
+        assert!(started.elapsed() < PREFLIGHT_TIMEOUT + Duration::from_secs(1));
+    }
+}
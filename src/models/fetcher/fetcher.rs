@@ -1,55 +1,295 @@
-use crate::events::{ActiveCount, EventType, FetcherRequest, FetcherResponse, StateUpdate};
-use crate::models::categories::CategoriesResponse;
-use reqwest::{Client, Error as ReqwestError};
-use tokio::sync::broadcast;
+use crate::events::{
+    recv_event_or_lag, report_lag, ActiveCount, EventType, FetcherRequest, FetcherResponse,
+    FileRequest, StateUpdate,
+};
+use crate::utils::Utils;
+use serde::de::DeserializeOwned;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{broadcast, Semaphore, SemaphorePermit};
+use tokio_util::sync::CancellationToken;
+use url::Url;
 
-pub struct Fetcher {
-    client: Client,
+use super::{DataSource, HttpSource};
+
+pub struct Fetcher<S: DataSource = HttpSource> {
+    source: Arc<S>,
+    limit: Arc<Limit>,
+    #[cfg(unix)]
+    concurrency: usize,
+    cancel: CancellationToken,
+    // Save every API response body under `raw/` before it's parsed
+    dump_raw: bool,
     sender: broadcast::Sender<EventType>,
     receiver: broadcast::Receiver<EventType>,
-    config: FetcherConfig,
 }
 
-#[derive(Clone, Debug)]
+// Caps how many requests are in flight at once. Lowering the cap takes back
+// the free permits right away and the rest from requests as they finish, so
+// it never waits behind requests queued for a permit
+struct Limit {
+    permits: Semaphore,
+    // Permits still to be taken back from requests in flight
+    debt: AtomicUsize,
+}
+
+// Held for the length of a request; given back on drop unless it pays off debt
+struct LimitPermit<'a> {
+    permit: Option<SemaphorePermit<'a>>,
+    debt: &'a AtomicUsize,
+}
+
+impl Limit {
+    fn new(permits: usize) -> Self {
+        Limit {
+            permits: Semaphore::new(permits),
+            debt: AtomicUsize::new(0),
+        }
+    }
+
+    async fn acquire(&self) -> Option<LimitPermit<'_>> {
+        let permit = self.permits.acquire().await.ok()?;
+        Some(LimitPermit {
+            permit: Some(permit),
+            debt: &self.debt,
+        })
+    }
+
+    #[cfg(unix)]
+    fn shrink(&self, by: usize) {
+        let free = self.permits.available_permits().min(by);
+        // Another request can take a free permit first; whatever isn't taken
+        // here is owed instead
+        let taken = match self.permits.try_acquire_many(free as u32) {
+            Ok(permits) => {
+                permits.forget();
+                free
+            }
+            Err(_) => 0,
+        };
+        self.debt.fetch_add(by - taken, Ordering::SeqCst);
+    }
+
+    #[cfg(unix)]
+    fn grow(&self, by: usize) {
+        // Debt not paid off yet is cancelled instead of adding permits
+        let debt = self
+            .debt
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |debt| {
+                Some(debt.saturating_sub(by))
+            })
+            .unwrap_or_default();
+        self.permits.add_permits(by - debt.min(by));
+    }
+}
+
+impl Drop for LimitPermit<'_> {
+    fn drop(&mut self) {
+        let paid = self
+            .debt
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |debt| {
+                debt.checked_sub(1)
+            })
+            .is_ok();
+        if let (true, Some(permit)) = (paid, self.permit.take()) {
+            permit.forget();
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct FetcherConfig {
     pub base_url: String,
+    // Plain http base urls are only accepted when this is set, e.g. for a local mock
+    pub allow_http: bool,
     pub language: String,
     pub email: String,
     pub password: String,
+    // Failed requests are retried up to this many times when the error is
+    // a connection problem or one of the `retry_on` statuses
+    pub max_retries: u32,
+    pub retry_on: Vec<u16>,
+    // Longest a Retry-After header can make a retry wait; None honors it in full
+    pub max_retry_after: Option<Duration>,
+    // Most retries allowed across the whole run; None scales it with the requests
+    pub retry_budget: Option<u64>,
+    // Requests in flight at once; also sizes the client's idle connection pool
+    pub concurrency: usize,
+    // Applied to the shared client's connections
+    pub tcp_keepalive: Duration,
+    pub connect_timeout: Duration,
+    // Theme assets bigger than this many bytes are skipped; None means no limit
+    pub max_file_size: Option<u64>,
+    // Hosts besides the base url's that may be sent credentials for attachments,
+    // e.g. a brand's own subdomain
+    pub attachment_hosts: Vec<String>,
 }
 
-impl Fetcher {
+// Written out by hand so the password never ends up in logs
+impl fmt::Debug for FetcherConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FetcherConfig")
+            .field("base_url", &self.base_url)
+            .field("allow_http", &self.allow_http)
+            .field("language", &self.language)
+            .field("email", &self.email)
+            .field("password", &"<redacted>")
+            .field("max_retries", &self.max_retries)
+            .field("retry_on", &self.retry_on)
+            .field("max_retry_after", &self.max_retry_after)
+            .field("retry_budget", &self.retry_budget)
+            .field("concurrency", &self.concurrency)
+            .field("tcp_keepalive", &self.tcp_keepalive)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("max_file_size", &self.max_file_size)
+            .field("attachment_hosts", &self.attachment_hosts)
+            .finish()
+    }
+}
+
+impl FetcherConfig {
+    // Endpoints are built as `{base_url}/api/v2/...`, so a trailing slash would
+    // double up. Parsing also lowercases the scheme and host; the path is kept as is
+    pub fn normalize_base_url(base_url: &str) -> String {
+        match Url::parse(base_url.trim()) {
+            Ok(url) => url.as_str().trim_end_matches('/').to_string(),
+            Err(_) => base_url.to_string(),
+        }
+    }
+
+    // Any host and port is allowed so Gov, EU and custom-domain instances work,
+    // but the base url must be a plain http(s) origin or path to build on
+    pub fn validate(&self) -> Result<(), String> {
+        let url = Url::parse(&self.base_url)
+            .map_err(|e| format!("Invalid base url {}: {}", self.base_url, e))?;
+
+        match url.scheme() {
+            "https" => {}
+            "http" if self.allow_http => {}
+            "http" => {
+                return Err(format!(
+                    "Base url must use https (set ZENDESK_ALLOW_HTTP=true to allow http): {}",
+                    self.base_url
+                ))
+            }
+            _ => {
+                return Err(format!(
+                    "Base url must use http or https: {}",
+                    self.base_url
+                ))
+            }
+        }
+        if url.host_str().is_none() {
+            return Err(format!("Base url has no host: {}", self.base_url));
+        }
+        if url.query().is_some() || url.fragment().is_some() {
+            return Err(format!(
+                "Base url can't have a query or fragment: {}",
+                self.base_url
+            ));
+        }
+        if self.connect_timeout.is_zero() {
+            return Err("Connect timeout must be greater than zero".to_string());
+        }
+        if self.concurrency == 0 {
+            return Err("Concurrency must be at least 1".to_string());
+        }
+        // Only error statuses ever reach the retry loop
+        if let Some(status) = self.retry_on.iter().find(|s| !(400..=599).contains(*s)) {
+            return Err(format!(
+                "Retry status must be a 4xx or 5xx code: {}",
+                status
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl FetcherConfig {
+    pub fn for_tests(base_url: &str) -> Self {
+        FetcherConfig {
+            base_url: base_url.to_string(),
+            allow_http: false,
+            language: "en-001".to_string(),
+            email: "agent@example.com".to_string(),
+            password: "secret".to_string(),
+            max_retries: 0,
+            retry_on: Vec::new(),
+            max_retry_after: None,
+            retry_budget: None,
+            concurrency: 1,
+            tcp_keepalive: Duration::from_secs(60),
+            connect_timeout: Duration::from_secs(30),
+            max_file_size: None,
+            attachment_hosts: Vec::new(),
+        }
+    }
+}
+
+impl<S: DataSource + Send + Sync + 'static> Fetcher<S> {
     pub fn new(
-        config: FetcherConfig,
+        source: S,
+        concurrency: usize,
+        dump_raw: bool,
         sender: broadcast::Sender<EventType>,
         receiver: broadcast::Receiver<EventType>,
     ) -> Self {
         Fetcher {
-            client: Client::new(),
+            source: Arc::new(source),
+            limit: Arc::new(Limit::new(concurrency)),
+            #[cfg(unix)]
+            concurrency,
+            cancel: CancellationToken::new(),
+            dump_raw,
             sender,
             receiver,
-            config,
         }
     }
 
     pub async fn run(&mut self) {
-        while let Ok(event) = self.receiver.recv().await {
+        #[cfg(unix)]
+        let signals = tokio::spawn(adjust_limit(self.limit.clone(), self.concurrency));
+
+        // Mark the fetcher started, so a run with nothing to fetch, such as a retry
+        // of a report without fetch failures, can still go inactive and finish
+        for action in [ActiveCount::Increment, ActiveCount::Decrement] {
+            let _ = self
+                .sender
+                .send(EventType::UpdateState(StateUpdate::Fetcher(action)));
+        }
+
+        while let Some(event) = recv_event_or_lag(&mut self.receiver).await {
+            // A lost request would leave its producer waiting on it for good
+            let event = match event {
+                Ok(event) => event,
+                Err(missed) => {
+                    report_lag(&self.sender, "Fetcher", missed);
+                    break;
+                }
+            };
             match event {
                 EventType::FetcherRequest(fetcher_request) => {
-                    let _ = self
-                        .sender
-                        .send(EventType::UpdateState(StateUpdate::Fetcher(
-                            ActiveCount::Increment,
-                        )));
-                    let response = self.handle_request(fetcher_request).await;
-                    if let Err(e) = self.sender.send(response) {
-                        eprintln!("Failed to communicate with event system: {}", e);
-                    }
-                    let _ = self
-                        .sender
-                        .send(EventType::UpdateState(StateUpdate::Fetcher(
-                            ActiveCount::Decrement,
-                        )));
+                    // Each request runs in its own task so the receiver keeps draining
+                    // the channel while requests wait for a permit
+                    let source = self.source.clone();
+                    let limit = self.limit.clone();
+                    let sender = self.sender.clone();
+                    let cancel = self.cancel.clone();
+                    let dump_raw = self.dump_raw;
+                    tokio::spawn(async move {
+                        // Shutdown abandons requests that are queued or still downloading
+                        tokio::select! {
+                            _ = cancel.cancelled() => {}
+                            _ = Self::process_request(&source, &limit, &sender, dump_raw, fetcher_request) => {}
+                        }
+                    });
                 }
                 EventType::Shutdown => {
                     println!("Fetcher service is shutting down.");
@@ -58,45 +298,284 @@ impl Fetcher {
                 _ => {} // Handle other event types or ignore
             }
         }
+
+        #[cfg(unix)]
+        signals.abort();
+        self.cancel.cancel();
     }
 
-    async fn handle_request(&self, fetcher_request: FetcherRequest) -> EventType {
-        match fetcher_request {
-            FetcherRequest::Categories(request_url) => {
-                let url = request_url.url;
-                match Fetcher::fetch_data(&self.client, &self.config, &url).await {
-                    Ok(data) => match serde_json::from_str::<CategoriesResponse>(&data) {
-                        Ok(categories_response) => EventType::FetcherResponse(
-                            FetcherResponse::Categories(categories_response),
-                        ),
-                        Err(_) => EventType::FetcherResponse(FetcherResponse::FetchFailed {
-                            error: "Invalid response format".to_string(),
-                        }),
-                    },
-                    Err(e) => EventType::FetcherResponse(FetcherResponse::FetchFailed {
-                        error: format!("Failed to fetch data: {}", e),
-                    }),
-                }
+    async fn process_request(
+        source: &S,
+        limit: &Limit,
+        sender: &broadcast::Sender<EventType>,
+        dump_raw: bool,
+        fetcher_request: FetcherRequest,
+    ) {
+        let Some(_permit) = limit.acquire().await else {
+            return;
+        };
+        let _ = sender.send(EventType::UpdateState(StateUpdate::Fetcher(
+            ActiveCount::Increment,
+        )));
+        // Tagged with the request's id so concurrent requests can be told apart
+        let id = fetcher_request.id();
+        println!("[{}] Fetching {}", id, fetcher_request.url());
+        let response = Self::handle_request(source, sender, dump_raw, fetcher_request).await;
+        match &response {
+            EventType::FetcherResponse(FetcherResponse::FetchFailed { error, .. }) => {
+                eprintln!("[{}] Failed: {}", id, error)
+            }
+            EventType::FetcherResponse(FetcherResponse::Skipped { reason, .. }) => {
+                println!("[{}] Skipped: {}", id, reason)
+            }
+            _ => println!("[{}] Done", id),
+        }
+        if let Err(e) = sender.send(response) {
+            eprintln!("Failed to communicate with event system: {}", e);
+        }
+        let _ = sender.send(EventType::UpdateState(StateUpdate::Fetcher(
+            ActiveCount::Decrement,
+        )));
+    }
+
+    async fn handle_request(
+        source: &S,
+        sender: &broadcast::Sender<EventType>,
+        dump_raw: bool,
+        fetcher_request: FetcherRequest,
+    ) -> EventType {
+        let data = match source.fetch(&fetcher_request).await {
+            Ok(data) => data,
+            Err(e) if e.skipped => {
+                return EventType::FetcherResponse(FetcherResponse::Skipped {
+                    request: fetcher_request,
+                    reason: e.message,
+                })
+            }
+            Err(e) => return Self::fetch_failed(fetcher_request, e.status, e.message),
+        };
+
+        if let Some(path) = raw_path(&fetcher_request).filter(|_| dump_raw) {
+            let _ = sender.send(EventType::FileRequest(FileRequest::Raw {
+                path,
+                data: data.clone(),
+            }));
+        }
+
+        match &fetcher_request {
+            FetcherRequest::Categories(_) => {
+                Self::parse_json(fetcher_request, &data, FetcherResponse::Categories)
+            }
+            FetcherRequest::Topics(_) => {
+                Self::parse_json(fetcher_request, &data, FetcherResponse::Topics)
+            }
+            FetcherRequest::Topic(_) => {
+                Self::parse_json(fetcher_request, &data, FetcherResponse::Topic)
+            }
+            FetcherRequest::Posts(_) => {
+                Self::parse_json(fetcher_request, &data, FetcherResponse::Posts)
+            }
+            FetcherRequest::ThemePage(request_url) => {
+                EventType::FetcherResponse(FetcherResponse::ThemePage {
+                    id: request_url.id,
+                    url: request_url.url.clone(),
+                    html: String::from_utf8_lossy(&data).into_owned(),
+                })
+            }
+            FetcherRequest::ThemeAsset(request_url) => {
+                EventType::FetcherResponse(FetcherResponse::ThemeAsset {
+                    id: request_url.id,
+                    url: request_url.url.clone(),
+                    data,
+                })
             } // Add other FetcherRequest cases here
         }
     }
 
-    async fn fetch_data(
-        client: &Client,
-        config: &FetcherConfig,
-        url: &str,
-    ) -> Result<String, ReqwestError> {
-        let endpoint = format!(
-            "{}/api/v2/help_center/{}/{}",
-            config.base_url, config.language, url
+    fn parse_json<T: DeserializeOwned>(
+        fetcher_request: FetcherRequest,
+        data: &[u8],
+        into_response: fn(u64, T) -> FetcherResponse,
+    ) -> EventType {
+        match serde_json::from_slice::<T>(data) {
+            Ok(response) => {
+                EventType::FetcherResponse(into_response(fetcher_request.id(), response))
+            }
+            Err(_) => {
+                Self::fetch_failed(fetcher_request, None, "Invalid response format".to_string())
+            }
+        }
+    }
+
+    fn fetch_failed(request: FetcherRequest, status: Option<u16>, error: String) -> EventType {
+        EventType::FetcherResponse(FetcherResponse::FetchFailed {
+            request,
+            status,
+            error,
+        })
+    }
+}
+
+// Raw responses mirror the API path, with the page number folded into the
+// file name, e.g. `raw/help_center/categories.page-2.json`. Theme pages and
+// assets are already written as they are, so they get no raw copy
+fn raw_path(request: &FetcherRequest) -> Option<String> {
+    let prefix = match request {
+        FetcherRequest::Categories(_) => "help_center",
+        FetcherRequest::Topics(_) | FetcherRequest::Topic(_) | FetcherRequest::Posts(_) => {
+            "community"
+        }
+        FetcherRequest::ThemePage(_) | FetcherRequest::ThemeAsset(_) => return None,
+    };
+
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    let page = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("page="));
+    let path = match page {
+        Some(page) => format!(
+            "{}.page-{}.json",
+            path.trim_end_matches(".json"),
+            Utils::sanitize_name(page)
+        ),
+        None => path.to_string(),
+    };
+    Some(format!("raw/{}/{}", prefix, path))
+}
+
+// SIGUSR1 halves the number of requests allowed in flight and SIGUSR2 restores
+// the configured limit, so an operator can back off mid-run without restarting
+#[cfg(unix)]
+async fn adjust_limit(limit: Arc<Limit>, concurrency: usize) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (Ok(mut slow_down), Ok(mut restore)) = (
+        signal(SignalKind::user_defined1()),
+        signal(SignalKind::user_defined2()),
+    ) else {
+        eprintln!("Failed to install concurrency signal handlers");
+        return;
+    };
+
+    let mut current = concurrency;
+    loop {
+        tokio::select! {
+            Some(()) = slow_down.recv() => {
+                let target = (current / 2).max(1);
+                // Requests already in flight finish; the cap applies as they do
+                limit.shrink(current - target);
+                current = target;
+                println!("SIGUSR1: concurrency lowered to {}", current);
+            }
+            Some(()) = restore.recv() => {
+                limit.grow(concurrency - current);
+                current = concurrency;
+                println!("SIGUSR2: concurrency restored to {}", current);
+            }
+            else => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{recv_event_until, RequestUrl};
+    use crate::models::fetcher::ScriptedSource;
+
+    async fn fetch(source: ScriptedSource, request: FetcherRequest) -> FetcherResponse {
+        let (tx, mut rx) = broadcast::channel(16);
+        let mut fetcher = Fetcher::new(source, 1, false, tx.clone(), tx.subscribe());
+        let handle = tokio::spawn(async move { fetcher.run().await });
+        let _ = tx.send(EventType::FetcherRequest(request));
+
+        let response = loop {
+            if let Some(EventType::FetcherResponse(response)) =
+                recv_event_until(&mut rx, "test", None).await
+            {
+                break response;
+            }
+        };
+        let _ = tx.send(EventType::Shutdown);
+        let _ = handle.await;
+        response
+    }
+
+    #[tokio::test]
+    async fn scripted_body_is_parsed_into_a_response() {
+        let source = ScriptedSource::default().respond(
+            "categories.json",
+            r#"{"categories": [], "next_page": null}"#,
+        );
+        let request = FetcherRequest::Categories(RequestUrl::new("categories.json".to_string()));
+        let id = request.id();
+
+        match fetch(source, request).await {
+            FetcherResponse::Categories(response_id, _) => assert_eq!(response_id, id),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[tokio::test]
+    async fn scripted_failure_is_reported_with_its_status() {
+        let source = ScriptedSource::default().fail("topics.json", 500);
+        let request = FetcherRequest::Topics(RequestUrl::new("topics.json".to_string()));
+
+        match fetch(source, request).await {
+            FetcherResponse::FetchFailed { status, .. } => assert_eq!(status, Some(500)),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn base_url_is_normalized() {
+        assert_eq!(
+            FetcherConfig::normalize_base_url("https://acme.zendesk.com/"),
+            "https://acme.zendesk.com"
+        );
+        assert_eq!(
+            FetcherConfig::normalize_base_url(" HTTPS://ACME.Zendesk.com//"),
+            "https://acme.zendesk.com"
         );
+        assert_eq!(
+            FetcherConfig::normalize_base_url("https://support.acme.com/Help/"),
+            "https://support.acme.com/Help"
+        );
+    }
+
+    #[test]
+    fn base_url_must_be_https_unless_http_is_allowed() {
+        let mut config = FetcherConfig::for_tests("http://localhost:8080");
+        assert!(config.validate().is_err());
+        config.allow_http = true;
+        assert!(config.validate().is_ok());
+        config.base_url = "https://acme.zendesk.com?x=1".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn lowering_the_limit_takes_permits_back_as_requests_finish() {
+        let limit = Limit::new(4);
+        let held = [
+            limit.acquire().await.unwrap(),
+            limit.acquire().await.unwrap(),
+        ];
+
+        // Two permits are free and taken right away, the other two are owed
+        limit.shrink(4);
+        assert_eq!(limit.permits.available_permits(), 0);
+        drop(held);
+        assert_eq!(limit.permits.available_permits(), 0);
 
-        let response = client
-            .get(&endpoint)
-            .basic_auth(&config.email, Some(&config.password))
-            .send()
-            .await?;
+        limit.grow(3);
+        assert_eq!(limit.permits.available_permits(), 3);
 
-        response.text().await.map_err(ReqwestError::from)
+        // Restoring before the debt is paid cancels it instead
+        let held = limit.acquire().await.unwrap();
+        limit.shrink(3);
+        limit.grow(3);
+        drop(held);
+        assert_eq!(limit.permits.available_permits(), 3);
     }
 }
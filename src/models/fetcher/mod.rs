@@ -1,3 +1,7 @@
+mod data_source;
 mod fetcher;
 
+#[cfg(test)]
+pub use data_source::ScriptedSource;
+pub use data_source::{DataSource, HttpSource};
 pub use fetcher::{Fetcher, FetcherConfig};
@@ -1,73 +1,317 @@
-use crate::events::{ActiveCount, EventType, FileRequest, StateUpdate};
-use std::path::Path;
-use tokio::{fs, sync::broadcast, task};
+use crate::events::{
+    recv_event_or_lag, report_lag, ActiveCount, EventType, FileRequest, FileWriterResponse,
+    StateUpdate,
+};
+use std::{io, path::Path};
+use tokio::{
+    fs,
+    process::Command,
+    sync::broadcast::{self, error::TryRecvError},
+};
 
 pub struct FileWriter {
     sender: broadcast::Sender<EventType>,
     receiver: broadcast::Receiver<EventType>,
-    base_path: String,
+    config: FileWriterConfig,
+}
+
+#[derive(Clone, Debug)]
+pub struct FileWriterConfig {
+    pub base_path: String,
+    // When set, everything is written under `{base_path}/{locale}/` so each
+    // locale's export gets its own tree
+    pub locale_dir: Option<String>,
+    // Run on every file after it's written, with the file's path appended as
+    // the last argument, e.g. "markdownlint --fix"
+    pub post_write_command: Option<String>,
 }
 
 impl FileWriter {
     pub fn new(
+        config: FileWriterConfig,
         sender: broadcast::Sender<EventType>,
         receiver: broadcast::Receiver<EventType>,
     ) -> Self {
         FileWriter {
             sender,
             receiver,
-            base_path: "data".to_string(),
+            config,
+        }
+    }
+
+    fn file_path(&self, path: &str) -> String {
+        match &self.config.locale_dir {
+            Some(locale) => format!("{}/{}/{}", self.config.base_path, locale, path),
+            None => format!("{}/{}", self.config.base_path, path),
+        }
+    }
+
+    // Promoting a directory onto itself, or into or out of itself, would rename
+    // files onto themselves and then delete the export, so the resolved paths
+    // must not overlap at all
+    pub fn check_staging_dir(staging: &str, output: &str) -> Result<(), String> {
+        let resolve = |dir: &str| {
+            std::fs::create_dir_all(dir)
+                .and_then(|_| std::fs::canonicalize(dir))
+                .map_err(|e| format!("Can't use directory {}: {}", dir, e))
+        };
+        let (staging_path, output_path) = (resolve(staging)?, resolve(output)?);
+        if staging_path.starts_with(&output_path) || output_path.starts_with(&staging_path) {
+            return Err(format!(
+                "ZENDESK_STAGING_DIR {} must be outside the output directory {}",
+                staging, output
+            ));
+        }
+        Ok(())
+    }
+
+    // Move every file under `from` to the same place under `to`, replacing what's
+    // there, then remove `from`. Renames fail across filesystems, so those files
+    // are copied instead
+    pub fn promote(from: &str, to: &str) -> io::Result<()> {
+        if !Path::new(from).exists() {
+            return Ok(());
         }
+        promote_dir(Path::new(from), Path::new(to))?;
+        std::fs::remove_dir_all(from)
     }
 
-    pub async fn run(&mut self) {
-        while let Ok(event) = self.receiver.recv().await {
+    // Every file request must be written, so a lag, which silently drops some,
+    // stops the export. Whatever is still queued is written either way, and the
+    // lag is returned as an error
+    pub async fn run(&mut self) -> Result<(), String> {
+        let mut missed = 0;
+        while let Some(event) = recv_event_or_lag(&mut self.receiver).await {
             match event {
-                EventType::FileRequest(file_request) => {
-                    let _ = self
-                        .sender
-                        .send(EventType::UpdateState(StateUpdate::FileWriter(
-                            ActiveCount::Increment,
-                        )));
-                    match file_request {
-                        FileRequest::Markdown { path, data } => {
-                            let file_path = format!("{}/{}", self.base_path, path);
-                            handle_file_write(&file_path, data.into()).await;
-                        }
-                        FileRequest::Image { path, data } => {
-                            let file_path = format!("{}/{}", self.base_path, path);
-                            handle_file_write(&file_path, data).await;
-                        }
-                    }
-                    let _ = self
-                        .sender
-                        .send(EventType::UpdateState(StateUpdate::FileWriter(
-                            ActiveCount::Decrement,
-                        )));
+                Ok(EventType::FileRequest(file_request)) => {
+                    self.process_request(file_request).await;
                 }
-                EventType::Shutdown => {
-                    println!("FileWriter service is shutting down.");
+                Ok(EventType::Shutdown) => break,
+                Ok(_) => {} // Handle other EventType variants if necessary
+                Err(lagged) => {
+                    report_lag(&self.sender, "FileWriter", lagged);
+                    missed += lagged;
                     break;
                 }
-                _ => {} // Handle other EventType variants if necessary
             }
         }
+
+        // Write anything still queued behind the shutdown so the tail of the
+        // export isn't dropped
+        loop {
+            match self.receiver.try_recv() {
+                Ok(EventType::FileRequest(file_request)) => {
+                    self.process_request(file_request).await;
+                }
+                Ok(_) => {}
+                Err(TryRecvError::Lagged(lagged)) => {
+                    eprintln!("FileWriter fell behind and missed {} events", lagged);
+                    missed += lagged;
+                }
+                Err(_) => break,
+            }
+        }
+        let _ = self.sender.send(EventType::FileWriterDrained);
+        println!("FileWriter service is shutting down.");
+
+        if missed > 0 {
+            return Err(format!(
+                "FileWriter missed {} events, some files were not written",
+                missed
+            ));
+        }
+        Ok(())
+    }
+
+    async fn process_request(&self, file_request: FileRequest) {
+        let _ = self
+            .sender
+            .send(EventType::UpdateState(StateUpdate::FileWriter(
+                ActiveCount::Increment,
+            )));
+        let (path, data) = match file_request {
+            FileRequest::Markdown { path, data } => (path, data.into()),
+            FileRequest::Image { path, data } | FileRequest::Raw { path, data } => (path, data),
+        };
+        let file_path = self.file_path(&path);
+        match handle_file_write(&file_path, data).await {
+            Ok(()) => {
+                if let Some(command) = &self.config.post_write_command {
+                    if let Err(error) = run_post_write_command(command, &file_path).await {
+                        eprintln!("Post-write command failed for {}: {}", file_path, error);
+                        let _ = self.sender.send(EventType::FileWriterResponse(
+                            FileWriterResponse::CommandFailed {
+                                path: file_path,
+                                error,
+                            },
+                        ));
+                    }
+                }
+            }
+            Err(error) => {
+                let _ = self.sender.send(EventType::FileWriterResponse(
+                    FileWriterResponse::WriteFailed {
+                        path: file_path,
+                        error,
+                    },
+                ));
+            }
+        }
+        let _ = self
+            .sender
+            .send(EventType::UpdateState(StateUpdate::FileWriter(
+                ActiveCount::Decrement,
+            )));
+    }
+}
+
+// The command isn't run through a shell; its output is passed through to the log
+async fn run_post_write_command(command: &str, path: &str) -> Result<(), String> {
+    let mut parts = command.split_whitespace();
+    let Some(program) = parts.next() else {
+        return Ok(());
+    };
+
+    let output = Command::new(program)
+        .args(parts)
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} exited with {}", program, output.status))
+    }
+}
+
+fn promote_dir(from: &Path, to: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            promote_dir(&entry.path(), &target)?;
+        } else if std::fs::rename(entry.path(), &target).is_err() {
+            std::fs::copy(entry.path(), &target)?;
+        }
     }
+    Ok(())
 }
 
-async fn handle_file_write(path: &str, data: Vec<u8>) {
+async fn handle_file_write(path: &str, data: Vec<u8>) -> Result<(), String> {
     let path = Path::new(path);
     if let Some(dir) = path.parent() {
         if !dir.exists() {
             if let Err(e) = fs::create_dir_all(dir).await {
                 eprintln!("Failed to create directory: {}", e);
-                return;
+                return Err(format!("Failed to create directory: {}", e));
             }
         }
     }
 
     match fs::write(path, &data).await {
-        Ok(_) => println!("File written successfully: {}", path.display()),
-        Err(e) => eprintln!("Failed to write file: {}", e),
+        Ok(_) => {
+            println!("File written successfully: {}", path.display());
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Failed to write file: {}", e);
+            Err(format!("Failed to write file: {}", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("zendesk_export_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.to_string_lossy().into_owned()
+    }
+
+    // The writer's own events go out on a separate channel, so they can't push
+    // the scripted ones out of a small buffer
+    fn writer(base_path: &str, capacity: usize) -> (FileWriter, broadcast::Sender<EventType>) {
+        let (tx, rx) = broadcast::channel(capacity);
+        let (out, _) = broadcast::channel(64);
+        let config = FileWriterConfig {
+            base_path: base_path.to_string(),
+            locale_dir: None,
+            post_write_command: None,
+        };
+        (FileWriter::new(config, out, rx), tx)
+    }
+
+    fn markdown(path: &str) -> EventType {
+        EventType::FileRequest(FileRequest::Markdown {
+            path: path.to_string(),
+            data: "# Title".to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn lag_is_reported_as_an_error() {
+        let dir = temp_dir("lag");
+        let (mut file_writer, tx) = writer(&dir, 2);
+        for name in ["a.md", "b.md", "c.md"] {
+            let _ = tx.send(markdown(name));
+        }
+        let _ = tx.send(EventType::Shutdown);
+
+        let result = file_writer.run().await;
+        assert!(result.is_err_and(|e| e.contains("missed 2 events")));
+        assert!(Path::new(&dir).join("c.md").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn staging_dir_must_not_overlap_the_output_dir() {
+        let dir = temp_dir("staging");
+        let output = format!("{}/data", dir);
+        for staging in [
+            format!("{}/data/", dir),
+            format!("{}/./data", dir),
+            format!("{}/data/staging", dir),
+            dir.clone(),
+        ] {
+            assert!(
+                FileWriter::check_staging_dir(&staging, &output).is_err(),
+                "{} was accepted",
+                staging
+            );
+        }
+        assert!(FileWriter::check_staging_dir(&format!("{}/staging", dir), &output).is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn requests_queued_behind_shutdown_are_written_before_drained() {
+        let dir = temp_dir("drain");
+        let (tx, rx) = broadcast::channel(16);
+        let (out, mut events) = broadcast::channel(16);
+        let config = FileWriterConfig {
+            base_path: dir.clone(),
+            locale_dir: None,
+            post_write_command: None,
+        };
+        let mut file_writer = FileWriter::new(config, out, rx);
+        let _ = tx.send(EventType::Shutdown);
+        let _ = tx.send(markdown("late.md"));
+
+        assert!(file_writer.run().await.is_ok());
+        assert!(Path::new(&dir).join("late.md").exists());
+        let mut drained = false;
+        while let Ok(event) = events.try_recv() {
+            drained = matches!(event, EventType::FileWriterDrained);
+        }
+        assert!(drained, "FileWriterDrained wasn't the last event");
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
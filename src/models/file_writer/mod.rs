@@ -1,3 +1,3 @@
 mod file_writer;
 
-pub use file_writer::FileWriter;
+pub use file_writer::{FileWriter, FileWriterConfig};
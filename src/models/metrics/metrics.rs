@@ -0,0 +1,132 @@
+use std::{collections::BTreeMap, fmt::Write, path::Path, time::Instant};
+use tokio::{fs, sync::broadcast, time};
+
+use crate::events::{recv_event_until, EventType, FetcherResponse, FileRequest, DRAIN_TIMEOUT};
+
+// Collects counters from the event stream and writes them in the Prometheus
+// text exposition format once the export shuts down and the file writer has
+// drained
+pub struct Metrics {
+    path: String,
+    started: Instant,
+    requests: BTreeMap<&'static str, u64>,
+    failures: u64,
+    retries: u64,
+    files: u64,
+    bytes: u64,
+    receiver: broadcast::Receiver<EventType>,
+}
+
+impl Metrics {
+    pub fn new(path: String, receiver: broadcast::Receiver<EventType>) -> Self {
+        Metrics {
+            path,
+            started: Instant::now(),
+            requests: BTreeMap::new(),
+            failures: 0,
+            retries: 0,
+            files: 0,
+            bytes: 0,
+            receiver,
+        }
+    }
+
+    pub async fn run(&mut self) {
+        // Files drained after Shutdown still count, as they do in the summary
+        let mut drain_deadline = None;
+        while let Some(event) =
+            recv_event_until(&mut self.receiver, "Metrics", drain_deadline).await
+        {
+            match event {
+                EventType::FetcherRequest(request) => {
+                    let kind = request.kind();
+                    *self.requests.entry(kind).or_insert(0) += 1;
+                }
+                EventType::FetcherResponse(FetcherResponse::FetchFailed { .. }) => {
+                    self.failures += 1;
+                }
+                EventType::FetchRetried => self.retries += 1,
+                EventType::FileRequest(request) => {
+                    let size = match request {
+                        FileRequest::Markdown { data, .. } => data.len(),
+                        FileRequest::Image { data, .. } => data.len(),
+                        FileRequest::Raw { .. } => continue,
+                    };
+                    self.files += 1;
+                    self.bytes += size as u64;
+                }
+                EventType::Shutdown => drain_deadline = Some(time::Instant::now() + DRAIN_TIMEOUT),
+                EventType::FileWriterDrained => break,
+                _ => {}
+            }
+        }
+
+        self.write_metrics().await;
+        println!("Metrics service is shutting down.");
+    }
+
+    async fn write_metrics(&self) {
+        let path = Path::new(&self.path);
+        if let Some(dir) = path.parent() {
+            if let Err(e) = fs::create_dir_all(dir).await {
+                eprintln!("Failed to create metrics directory: {}", e);
+                return;
+            }
+        }
+
+        match fs::write(path, self.render()).await {
+            Ok(_) => println!("Metrics written successfully: {}", path.display()),
+            Err(e) => eprintln!("Failed to write metrics: {}", e),
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP zendesk_export_requests_total Fetch requests issued."
+        );
+        let _ = writeln!(out, "# TYPE zendesk_export_requests_total counter");
+        for (kind, count) in &self.requests {
+            let _ = writeln!(
+                out,
+                "zendesk_export_requests_total{{type=\"{}\"}} {}",
+                kind, count
+            );
+        }
+
+        let counters = [
+            (
+                "fetch_failures_total",
+                "Fetch requests that failed.",
+                self.failures,
+            ),
+            (
+                "retries_total",
+                "Fetch attempts that were retried.",
+                self.retries,
+            ),
+            ("files_total", "Files queued for writing.", self.files),
+            ("file_bytes_total", "Bytes queued for writing.", self.bytes),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP zendesk_export_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE zendesk_export_{} counter", name);
+            let _ = writeln!(out, "zendesk_export_{} {}", name, value);
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP zendesk_export_duration_seconds Wall time of the export."
+        );
+        let _ = writeln!(out, "# TYPE zendesk_export_duration_seconds gauge");
+        let _ = writeln!(
+            out,
+            "zendesk_export_duration_seconds {:.3}",
+            self.started.elapsed().as_secs_f64()
+        );
+
+        out
+    }
+}
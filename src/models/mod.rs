@@ -1,5 +1,10 @@
 pub mod app_state;
 pub mod categories;
+pub mod community;
+pub mod failure_report;
 pub mod fetcher;
 pub mod file_writer;
-
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod summary;
+pub mod theme_assets;
@@ -0,0 +1,3 @@
+mod summary;
+
+pub use summary::Summary;
@@ -0,0 +1,137 @@
+use reqwest::Client;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tokio::{sync::broadcast, time};
+
+use crate::events::{
+    recv_event_until, EventType, FetcherResponse, FileRequest, FileWriterResponse, DRAIN_TIMEOUT,
+};
+
+// The webhook is posted after the actors have stopped, outside the shutdown
+// grace period, so it gets its own bound
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct ExportSummary {
+    pub requests: u64,
+    pub failed_requests: u64,
+    // Requests still unanswered when the export shut down
+    pub cancelled_requests: u64,
+    // Left out on purpose, e.g. over the size limit
+    pub skipped_requests: u64,
+    pub retries: u64,
+    pub files_written: u64,
+    pub failed_writes: u64,
+    pub duration_seconds: f64,
+}
+
+// Tallies the export as it runs, prints the totals at shutdown and, when a
+// notify url is configured, posts them there as JSON
+pub struct Summary {
+    summary: ExportSummary,
+    responses: u64,
+    started: Instant,
+    notify_url: Option<String>,
+    client: Client,
+    receiver: broadcast::Receiver<EventType>,
+}
+
+impl Summary {
+    pub fn new(
+        notify_url: Option<String>,
+        client: Client,
+        receiver: broadcast::Receiver<EventType>,
+    ) -> Self {
+        Summary {
+            summary: ExportSummary::default(),
+            responses: 0,
+            started: Instant::now(),
+            notify_url,
+            client,
+            receiver,
+        }
+    }
+
+    // Returns an error only if the completion notification couldn't be delivered
+    pub async fn run(&mut self) -> Result<(), String> {
+        // Writes queued behind Shutdown still count, so the totals wait for the
+        // file writer to drain
+        let mut drain_deadline = None;
+        while let Some(event) =
+            recv_event_until(&mut self.receiver, "Summary", drain_deadline).await
+        {
+            match event {
+                EventType::FetcherRequest(_) => self.summary.requests += 1,
+                EventType::FetcherResponse(response) => {
+                    self.responses += 1;
+                    match response {
+                        FetcherResponse::FetchFailed { .. } => self.summary.failed_requests += 1,
+                        FetcherResponse::Skipped { .. } => self.summary.skipped_requests += 1,
+                        _ => {}
+                    }
+                }
+                EventType::FetchRetried => self.summary.retries += 1,
+                EventType::FileRequest(FileRequest::Raw { .. }) => {}
+                EventType::FileRequest(_) => self.summary.files_written += 1,
+                EventType::FileWriterResponse(FileWriterResponse::WriteFailed { .. }) => {
+                    self.summary.files_written = self.summary.files_written.saturating_sub(1);
+                    self.summary.failed_writes += 1;
+                }
+                EventType::Shutdown => {
+                    self.summary.duration_seconds = self.started.elapsed().as_secs_f64();
+                    // The fetcher drops whatever it hasn't answered once Shutdown is sent
+                    self.summary.cancelled_requests =
+                        self.summary.requests.saturating_sub(self.responses);
+                    drain_deadline = Some(time::Instant::now() + DRAIN_TIMEOUT);
+                }
+                EventType::FileWriterDrained => break,
+                _ => {}
+            }
+        }
+
+        self.print_summary();
+        println!("Summary service is shutting down.");
+        self.notify().await
+    }
+
+    fn print_summary(&self) {
+        let summary = &self.summary;
+        println!(
+            "Export finished in {:.1}s: {} requests ({} failed, {} cancelled, {} skipped, {} retries), {} files written ({} failed)",
+            summary.duration_seconds,
+            summary.requests,
+            summary.failed_requests,
+            summary.cancelled_requests,
+            summary.skipped_requests,
+            summary.retries,
+            summary.files_written,
+            summary.failed_writes
+        );
+    }
+
+    async fn notify(&self) -> Result<(), String> {
+        let Some(url) = &self.notify_url else {
+            return Ok(());
+        };
+
+        let result = self
+            .client
+            .post(url)
+            .timeout(NOTIFY_TIMEOUT)
+            .json(&self.summary)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        match result {
+            Ok(_) => {
+                println!("Export summary sent to {}", url);
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("Failed to send export summary to {}: {}", url, e);
+                Err(format!("Failed to send export summary: {}", e))
+            }
+        }
+    }
+}
@@ -0,0 +1,3 @@
+mod theme_assets;
+
+pub use theme_assets::ThemeAssets;
@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+use tokio::sync::broadcast;
+use url::Url;
+
+use crate::events::{
+    recv_event_or_lag, report_lag, ActiveCount, EventType, FetcherRequest, FetcherResponse,
+    FileRequest, RequestUrl, StateUpdate,
+};
+
+// Only files the theme actually serves; links to other pages are ignored
+const ASSET_EXTENSIONS: [&str; 12] = [
+    "css", "js", "png", "jpg", "jpeg", "gif", "svg", "ico", "webp", "woff", "woff2", "ttf",
+];
+
+#[derive(Debug)]
+pub struct ThemeAssets {
+    initial_requests: Vec<FetcherRequest>,
+    requested: HashSet<String>,
+    pending: HashSet<String>,
+    sender: broadcast::Sender<EventType>,
+    receiver: broadcast::Receiver<EventType>,
+}
+
+impl ThemeAssets {
+    pub fn new(
+        page_url: String,
+        sender: broadcast::Sender<EventType>,
+        receiver: broadcast::Receiver<EventType>,
+    ) -> Self {
+        ThemeAssets {
+            initial_requests: vec![FetcherRequest::ThemePage(RequestUrl::new(page_url))],
+            requested: HashSet::new(),
+            pending: HashSet::new(),
+            sender,
+            receiver,
+        }
+    }
+
+    // Start from these requests instead of the Help Center page
+    pub fn seed(&mut self, requests: &[FetcherRequest]) {
+        self.initial_requests = requests
+            .iter()
+            .filter(|request| {
+                matches!(
+                    request,
+                    FetcherRequest::ThemePage(_) | FetcherRequest::ThemeAsset(_)
+                )
+            })
+            .cloned()
+            .collect();
+    }
+
+    pub async fn run(&mut self) {
+        // Hold the service active while seeding, so it still goes inactive
+        // when there is nothing to request
+        self.update_state(ActiveCount::Increment);
+        for request in std::mem::take(&mut self.initial_requests) {
+            match request {
+                FetcherRequest::ThemePage(request_url) => {
+                    self.request(request_url.url, FetcherRequest::ThemePage)
+                }
+                FetcherRequest::ThemeAsset(request_url) => {
+                    self.requested.insert(request_url.url.clone());
+                    self.request(request_url.url, FetcherRequest::ThemeAsset)
+                }
+                _ => {}
+            }
+        }
+        self.update_state(ActiveCount::Decrement);
+
+        while let Some(message) = recv_event_or_lag(&mut self.receiver).await {
+            // A lost response would never be handled or counted down, so a lag
+            // ends the export instead of leaving it to stall
+            let message = match message {
+                Ok(message) => message,
+                Err(missed) => {
+                    report_lag(&self.sender, "ThemeAssets", missed);
+                    break;
+                }
+            };
+            match message {
+                EventType::FetcherResponse(response) => {
+                    self.process_response(response).await;
+                }
+                EventType::Shutdown => {
+                    println!("ThemeAssets service is shutting down.");
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn process_response(&mut self, response: FetcherResponse) {
+        match response {
+            FetcherResponse::ThemePage { id, url, html } if self.pending.contains(&url) => {
+                let assets = discover_assets(&url, &html);
+                println!("[{}] {} assets referenced by {}", id, assets.len(), url);
+                for asset_url in assets {
+                    if self.requested.insert(asset_url.clone()) {
+                        self.request(asset_url, FetcherRequest::ThemeAsset);
+                    }
+                }
+                self.complete(&url);
+            }
+            FetcherResponse::ThemeAsset { id, url, data } if self.pending.contains(&url) => {
+                println!("[{}] Asset {}", id, url);
+                if let Some(path) = asset_path(&url) {
+                    let _ = self
+                        .sender
+                        .send(EventType::FileRequest(FileRequest::Image { path, data }));
+                }
+                self.complete(&url);
+            }
+            FetcherResponse::FetchFailed {
+                request:
+                    FetcherRequest::ThemePage(request_url) | FetcherRequest::ThemeAsset(request_url),
+                error,
+                ..
+            } if self.pending.contains(&request_url.url) => {
+                eprintln!(
+                    "[{}] Fetch failed for {}: {}",
+                    request_url.id, request_url.url, error
+                );
+                self.complete(&request_url.url);
+            }
+            FetcherResponse::Skipped {
+                request: FetcherRequest::ThemeAsset(request_url),
+                ..
+            } if self.pending.contains(&request_url.url) => {
+                self.complete(&request_url.url);
+            }
+            _ => {}
+        }
+    }
+
+    // Every request stays active until its response has been handled, so the
+    // export can't finish while assets are still being discovered or downloaded
+    fn request(&mut self, url: String, request: fn(RequestUrl) -> FetcherRequest) {
+        self.pending.insert(url.clone());
+        self.update_state(ActiveCount::Increment);
+        let _ = self
+            .sender
+            .send(EventType::FetcherRequest(request(RequestUrl::new(url))));
+    }
+
+    fn complete(&mut self, url: &str) {
+        self.pending.remove(url);
+        self.update_state(ActiveCount::Decrement);
+    }
+
+    fn update_state(&self, action: ActiveCount) {
+        let _ = self
+            .sender
+            .send(EventType::UpdateState(StateUpdate::ThemeAssets(action)));
+    }
+}
+
+// Collect the absolute urls of every asset referenced by `href` or `src` on the page
+fn discover_assets(page_url: &str, html: &str) -> Vec<String> {
+    let Ok(base) = Url::parse(page_url) else {
+        return Vec::new();
+    };
+
+    let mut assets = Vec::new();
+    for attribute in ["href=", "src="] {
+        for (index, _) in html.match_indices(attribute) {
+            let rest = &html[index + attribute.len()..];
+            let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+                continue;
+            };
+            let Some(end) = rest[1..].find(quote) else {
+                continue;
+            };
+
+            if let Ok(url) = base.join(&rest[1..end + 1]) {
+                if is_asset(&url) {
+                    assets.push(url.to_string());
+                }
+            }
+        }
+    }
+    assets
+}
+
+fn is_asset(url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https")
+        && url
+            .path()
+            .rsplit_once('.')
+            .map(|(_, extension)| ASSET_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+            .unwrap_or(false)
+}
+
+// Mirror the asset's host and path under `assets/` so files from different
+// directories can't overwrite each other
+fn asset_path(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    let path = url
+        .path_segments()?
+        .filter(|segment| !segment.is_empty() && *segment != "..")
+        .collect::<Vec<_>>()
+        .join("/");
+
+    Some(format!("assets/{}/{}", host, path))
+}
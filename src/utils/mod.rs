@@ -1,3 +1,3 @@
 mod utils;
 
-pub use utils::Utils;
+pub use utils::{FrontMatterConfig, Utils};
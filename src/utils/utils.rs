@@ -1,23 +1,172 @@
 pub struct Utils;
 
+#[derive(Clone, Debug)]
+pub struct FrontMatterConfig {
+    // When false, files hold just the body with no YAML header at all
+    pub enabled: bool,
+    // Add the page's Zendesk url as `source_url`
+    pub source_url: bool,
+    // Reformats dates; None keeps Zendesk's RFC 3339 timestamps
+    pub date_format: Option<String>,
+}
+
+impl FrontMatterConfig {
+    pub fn render(
+        &self,
+        title: &str,
+        html_url: &Option<String>,
+        mut fields: Vec<(&str, String)>,
+    ) -> String {
+        if !self.enabled {
+            return String::new();
+        }
+        if let Some(url) = html_url.as_ref().filter(|_| self.source_url) {
+            fields.push(("source_url", url.clone()));
+        }
+        Utils::create_front_matter_with_fields(title, &fields)
+    }
+
+    pub fn date(&self, timestamp: String) -> String {
+        match &self.date_format {
+            Some(format) => Utils::format_timestamp(&timestamp, format),
+            None => timestamp,
+        }
+    }
+}
+
 impl Utils {
     pub fn sanitize_name(name: &str) -> String {
-        name.chars()
+        let mut sanitized = String::with_capacity(name.len());
+        let mut previous_separator = true;
+
+        // Runs of separators collapse into their first one, and separators
+        // at either end are dropped
+        for c in name
+            .chars()
             .filter(|c| c.is_alphanumeric() || *c == ' ' || *c == '-' || *c == '_')
             .map(|c| if c == ' ' { '_' } else { c })
-            .collect()
+        {
+            let is_separator = c == '_' || c == '-';
+            if !(is_separator && previous_separator) {
+                sanitized.push(c);
+            }
+            previous_separator = is_separator;
+        }
+
+        if sanitized.ends_with(['_', '-']) {
+            sanitized.pop();
+        }
+        sanitized
     }
 
     // get rid of cat and tag
     // pub fn convert_html_to_markdown(html: &str, title: &str) -> String {
     //     let markdown_content = html2md::parse_html(html);
     //
-    //     let front_matter = Self::create_front_matter(title);
+    //     let front_matter = Self::create_front_matter_with_fields(title, &[]);
     //
     //     format!("{}{}", front_matter, markdown_content)
     // }
 
-    pub fn create_front_matter(title: &str) -> String {
-        return format!("---\ntitle: \"{}\"\n---\n\n", title);
+    pub fn create_front_matter_with_fields(title: &str, fields: &[(&str, String)]) -> String {
+        let mut front_matter = format!("---\ntitle: {}\n", Self::yaml_string(title));
+        for (key, value) in fields {
+            front_matter.push_str(&format!("{}: {}\n", key, Self::yaml_string(value)));
+        }
+        front_matter.push_str("---\n\n");
+        front_matter
+    }
+
+    // Reformat an RFC 3339 timestamp such as `2024-05-01T09:30:00Z` with a
+    // strftime-style format. Only %Y %m %d %H %M %S and %% are supported, which
+    // covers the date formats static site generators want
+    pub fn format_timestamp(timestamp: &str, format: &str) -> String {
+        let field = |range: std::ops::Range<usize>| timestamp.get(range).unwrap_or("");
+        let bytes = timestamp.as_bytes();
+        if bytes.len() < 19 || bytes[4] != b'-' || bytes[7] != b'-' || bytes[13] != b':' {
+            return timestamp.to_string();
+        }
+
+        let mut formatted = String::with_capacity(format.len());
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                formatted.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('Y') => formatted.push_str(field(0..4)),
+                Some('m') => formatted.push_str(field(5..7)),
+                Some('d') => formatted.push_str(field(8..10)),
+                Some('H') => formatted.push_str(field(11..13)),
+                Some('M') => formatted.push_str(field(14..16)),
+                Some('S') => formatted.push_str(field(17..19)),
+                _ => formatted.push('%'),
+            }
+        }
+        formatted
+    }
+
+    pub fn validate_date_format(format: &str) -> Result<(), String> {
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c == '%' && !matches!(chars.next(), Some('Y' | 'm' | 'd' | 'H' | 'M' | 'S' | '%')) {
+                return Err(format!(
+                    "Unsupported date format {}: only %Y %m %d %H %M %S and %% are allowed",
+                    format
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Double-quoted YAML scalar. Inside double quotes only backslashes, quotes
+    // and control characters need escaping; leading `@`, `%`, `:` etc. are safe
+    fn yaml_string(value: &str) -> String {
+        let mut quoted = String::with_capacity(value.len() + 2);
+        quoted.push('"');
+        for c in value.chars() {
+            match c {
+                '\\' => quoted.push_str("\\\\"),
+                '"' => quoted.push_str("\\\""),
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_name_collapses_separators() {
+        assert_eq!(Utils::sanitize_name("Getting  started"), "Getting_started");
+        assert_eq!(Utils::sanitize_name("A - B"), "A_B");
+        assert_eq!(Utils::sanitize_name("Q&A / FAQ"), "QA_FAQ");
+        assert_eq!(Utils::sanitize_name(" -Edge_ "), "Edge");
+        assert_eq!(Utils::sanitize_name("--_--"), "");
+    }
+
+    #[test]
+    fn front_matter_escapes_yaml_strings() {
+        let front_matter = Utils::create_front_matter_with_fields(
+            r#"Say "hi" \ bye"#,
+            &[
+                ("author", "@support".to_string()),
+                ("share", "%50".to_string()),
+                ("summary", "Step 1: install\nnext".to_string()),
+            ],
+        );
+        assert_eq!(
+            front_matter,
+            "---\ntitle: \"Say \\\"hi\\\" \\\\ bye\"\nauthor: \"@support\"\nshare: \"%50\"\nsummary: \"Step 1: install\\nnext\"\n---\n\n"
+        );
     }
 }